@@ -2,7 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-#![feature(box_syntax, plugin, std_misc)]
+#![feature(alloc, box_syntax, plugin, std_misc)]
 #![cfg_attr(test, feature(test, hash))]
 #![plugin(string_cache_plugin)]
 
@@ -19,3 +19,4 @@ pub mod fnv;
 pub mod matching;
 pub mod parser;
 pub mod tree;
+pub mod visitor;
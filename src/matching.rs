@@ -15,9 +15,15 @@ use quicksort::quicksort_by;
 use string_cache::Atom;
 
 use fnv::FnvHasher;
-use parser::{CaseSensitivity, Combinator, CompoundSelector, LocalName};
-use parser::{SimpleSelector, Selector};
-use tree::{TElement, TNode};
+use parser::{AttrSelector, CaseSensitivity, Combinator, CompoundSelector, LocalName};
+use parser::{NamespaceConstraint, PseudoElement, RelativeSelector, SelectorImpl};
+use parser::{SimpleSelector, Selector, Specificity};
+use tree::{IN_ACTIVE_STATE, IN_CHECKED_STATE, IN_DEFAULT_STATE, IN_DEFINED_STATE};
+use tree::{IN_DISABLED_STATE, IN_ENABLED_STATE, IN_FOCUS_STATE, IN_FOCUS_VISIBLE_STATE};
+use tree::{IN_FOCUS_WITHIN_STATE, IN_HOVER_STATE, IN_INDETERMINATE_STATE, IN_INVALID_STATE};
+use tree::{IN_IN_RANGE_STATE, IN_OPTIONAL_STATE, IN_OUT_OF_RANGE_STATE};
+use tree::{IN_PLACEHOLDER_SHOWN_STATE, IN_READ_ONLY_STATE, IN_READ_WRITE_STATE};
+use tree::{IN_REQUIRED_STATE, IN_VALID_STATE, TElement, TNode};
 
 /// The definition of whitespace per CSS Selectors Level 3 § 4.
 pub static SELECTOR_WHITESPACE: &'static [char] = &[' ', '\t', '\n', '\r', '\x0C'];
@@ -41,23 +47,35 @@ pub static SELECTOR_WHITESPACE: &'static [char] = &[' ', '\t', '\n', '\r', '\x0C
 /// Hence, the union of the rules keyed on each of node's classes, ID,
 /// element name, etc. will contain the Rules that actually match that
 /// node.
-pub struct SelectorMap<T> {
+///
+/// A map serves the documents of one quirks mode, which must be that of the `MatchingContext`
+/// passed to `get_all_matching_rules`.
+pub struct SelectorMap<T, Impl: SelectorImpl> {
+    /// In quirks mode, where IDs and classes match case-insensitively, the keys of id_hash and
+    /// class_hash are ASCII-lower-cased.
+    quirks_mode: QuirksMode,
     // TODO: Tune the initial capacity of the HashMap
-    id_hash: HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
-    class_hash: HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
-    local_name_hash: HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
+    id_hash: HashMap<Atom, Vec<Rule<T, Impl>>, DefaultState<FnvHasher>>,
+    class_hash: HashMap<Atom, Vec<Rule<T, Impl>>, DefaultState<FnvHasher>>,
+    local_name_hash: HashMap<Atom, Vec<Rule<T, Impl>>, DefaultState<FnvHasher>>,
     /// Same as local_name_hash, but keys are lower-cased.
     /// For HTML elements in HTML documents.
-    lower_local_name_hash: HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
+    lower_local_name_hash: HashMap<Atom, Vec<Rule<T, Impl>>, DefaultState<FnvHasher>>,
     // For Rules that don't have ID, class, or element selectors.
-    universal_rules: Vec<Rule<T>>,
+    universal_rules: Vec<Rule<T, Impl>>,
     /// Whether this hash is empty.
     empty: bool,
 }
 
-impl<T> SelectorMap<T> {
-    pub fn new() -> SelectorMap<T> {
+impl<T, Impl: SelectorImpl> SelectorMap<T, Impl> {
+    /// A map for documents that are not in quirks mode.
+    pub fn new() -> SelectorMap<T, Impl> {
+        SelectorMap::with_quirks_mode(QuirksMode::NoQuirks)
+    }
+
+    pub fn with_quirks_mode(quirks_mode: QuirksMode) -> SelectorMap<T, Impl> {
         SelectorMap {
+            quirks_mode: quirks_mode,
             id_hash: HashMap::with_hash_state(Default::default()),
             class_hash: HashMap::with_hash_state(Default::default()),
             local_name_hash: HashMap::with_hash_state(Default::default()),
@@ -74,9 +92,11 @@ impl<T> SelectorMap<T> {
     pub fn get_all_matching_rules<'a,N,V>(&self,
                                           node: &N,
                                           parent_bf: &Option<Box<BloomFilter>>,
+                                          context: &mut MatchingContext,
                                           matching_rules_list: &mut V,
                                           shareable: &mut bool)
                                           where N: TNode<'a>,
+                                                N::Element: TElement<'a, Impl=Impl>,
                                                 V: VecLike<DeclarationBlock<T>> {
         if self.empty {
             return
@@ -85,10 +105,15 @@ impl<T> SelectorMap<T> {
         // At the end, we're going to sort the rules that we added, so remember where we began.
         let init_len = matching_rules_list.len();
         let element = node.as_element();
+        let quirks_mode = self.quirks_mode == QuirksMode::Quirks;
+        debug_assert!(quirks_mode == (context.quirks_mode == QuirksMode::Quirks),
+                      "SelectorMap used in another quirks mode than the one it was built for");
         match element.get_id() {
             Some(id) => {
+                let id = if quirks_mode { to_ascii_lowercase_atom(&id) } else { id };
                 SelectorMap::get_matching_rules_from_hash(node,
                                                           parent_bf,
+                                                          context,
                                                           &self.id_hash,
                                                           &id,
                                                           matching_rules_list,
@@ -98,8 +123,16 @@ impl<T> SelectorMap<T> {
         }
 
         element.each_class(|class| {
+            let lower_class;
+            let class = if quirks_mode {
+                lower_class = to_ascii_lowercase_atom(class);
+                &lower_class
+            } else {
+                class
+            };
             SelectorMap::get_matching_rules_from_hash(node,
                                                       parent_bf,
+                                                      context,
                                                       &self.class_hash,
                                                       class,
                                                       matching_rules_list,
@@ -113,6 +146,7 @@ impl<T> SelectorMap<T> {
         };
         SelectorMap::get_matching_rules_from_hash(node,
                                                   parent_bf,
+                                                  context,
                                                   local_name_hash,
                                                   element.get_local_name(),
                                                   matching_rules_list,
@@ -120,6 +154,7 @@ impl<T> SelectorMap<T> {
 
         SelectorMap::get_matching_rules(node,
                                         parent_bf,
+                                        context,
                                         &self.universal_rules,
                                         matching_rules_list,
                                         shareable);
@@ -134,18 +169,21 @@ impl<T> SelectorMap<T> {
 
     fn get_matching_rules_from_hash<'a,N,V>(node: &N,
                                             parent_bf: &Option<Box<BloomFilter>>,
+                                            context: &mut MatchingContext,
                                             hash: &HashMap<Atom,
-                                                           Vec<Rule<T>>,
+                                                           Vec<Rule<T, Impl>>,
                                                            DefaultState<FnvHasher>>,
                                             key: &Atom,
                                             matching_rules: &mut V,
                                             shareable: &mut bool)
                                             where N: TNode<'a>,
+                                                  N::Element: TElement<'a, Impl=Impl>,
                                                   V: VecLike<DeclarationBlock<T>> {
         match hash.get(key) {
             Some(rules) => {
                 SelectorMap::get_matching_rules(node,
                                                 parent_bf,
+                                                context,
                                                 rules,
                                                 matching_rules,
                                                 shareable)
@@ -157,13 +195,15 @@ impl<T> SelectorMap<T> {
     /// Adds rules in `rules` that match `node` to the `matching_rules` list.
     fn get_matching_rules<'a,N,V>(node: &N,
                                   parent_bf: &Option<Box<BloomFilter>>,
-                                  rules: &[Rule<T>],
+                                  context: &mut MatchingContext,
+                                  rules: &[Rule<T, Impl>],
                                   matching_rules: &mut V,
                                   shareable: &mut bool)
                                   where N: TNode<'a>,
+                                        N::Element: TElement<'a, Impl=Impl>,
                                         V: VecLike<DeclarationBlock<T>> {
         for rule in rules.iter() {
-            if matches_compound_selector(&*rule.selector, node, parent_bf, shareable) {
+            if matches_selector(&rule.selector, node, parent_bf, context, shareable) {
                 matching_rules.push(rule.declarations.clone());
             }
         }
@@ -171,11 +211,13 @@ impl<T> SelectorMap<T> {
 
     /// Insert rule into the correct hash.
     /// Order in which to try: id_hash, class_hash, local_name_hash, universal_rules.
-    pub fn insert(&mut self, rule: Rule<T>) {
+    pub fn insert(&mut self, rule: Rule<T, Impl>) {
         self.empty = false;
 
+        let quirks_mode = self.quirks_mode == QuirksMode::Quirks;
         match SelectorMap::get_id_name(&rule) {
             Some(id_name) => {
+                let id_name = if quirks_mode { to_ascii_lowercase_atom(&id_name) } else { id_name };
                 find_push(&mut self.id_hash, id_name, rule);
                 return;
             }
@@ -183,6 +225,11 @@ impl<T> SelectorMap<T> {
         }
         match SelectorMap::get_class_name(&rule) {
             Some(class_name) => {
+                let class_name = if quirks_mode {
+                    to_ascii_lowercase_atom(&class_name)
+                } else {
+                    class_name
+                };
                 find_push(&mut self.class_hash, class_name, rule);
                 return;
             }
@@ -202,45 +249,33 @@ impl<T> SelectorMap<T> {
     }
 
     /// Retrieve the first ID name in Rule, or None otherwise.
-    fn get_id_name(rule: &Rule<T>) -> Option<Atom> {
-        let simple_selector_sequence = &rule.selector.simple_selectors;
-        for ss in simple_selector_sequence.iter() {
+    fn get_id_name(rule: &Rule<T, Impl>) -> Option<Atom> {
+        rule.selector.complex_selector.rightmost_compound().simple_selectors().filter_map(|ss| {
             match *ss {
-                // TODO(pradeep): Implement case-sensitivity based on the document type and quirks
-                // mode.
-                SimpleSelector::ID(ref id) => return Some(id.clone()),
-                _ => {}
+                SimpleSelector::ID(ref id) => Some(id.clone()),
+                _ => None,
             }
-        }
-        return None
+        }).next()
     }
 
     /// Retrieve the FIRST class name in Rule, or None otherwise.
-    fn get_class_name(rule: &Rule<T>) -> Option<Atom> {
-        let simple_selector_sequence = &rule.selector.simple_selectors;
-        for ss in simple_selector_sequence.iter() {
+    fn get_class_name(rule: &Rule<T, Impl>) -> Option<Atom> {
+        rule.selector.complex_selector.rightmost_compound().simple_selectors().filter_map(|ss| {
             match *ss {
-                // TODO(pradeep): Implement case-sensitivity based on the document type and quirks
-                // mode.
-                SimpleSelector::Class(ref class) => return Some(class.clone()),
-                _ => {}
+                SimpleSelector::Class(ref class) => Some(class.clone()),
+                _ => None,
             }
-        }
-        return None
+        }).next()
     }
 
     /// Retrieve the name if it is a type selector, or None otherwise.
-    fn get_local_name(rule: &Rule<T>) -> Option<LocalName> {
-        let simple_selector_sequence = &rule.selector.simple_selectors;
-        for ss in simple_selector_sequence.iter() {
+    fn get_local_name(rule: &Rule<T, Impl>) -> Option<LocalName> {
+        rule.selector.complex_selector.rightmost_compound().simple_selectors().filter_map(|ss| {
             match *ss {
-                SimpleSelector::LocalName(ref name) => {
-                    return Some(name.clone())
-                }
-                _ => {}
+                SimpleSelector::LocalName(ref name) => Some(name.clone()),
+                _ => None,
             }
-        }
-        return None
+        }).next()
     }
 }
 
@@ -249,12 +284,53 @@ impl<T> SelectorMap<T> {
 // rapidly increase.
 pub static RECOMMENDED_SELECTOR_BLOOM_FILTER_SIZE: usize = 4096;
 
+/// Adds the local name, namespace, ID and classes of `element` to `bf`, the bloom filter of the
+/// ancestors of the elements that get matched. In quirks mode, the ID and classes are added
+/// ASCII-lowercased, which is what matching looks for.
+pub fn insert_into_bloom_filter<'a,E>(bf: &mut BloomFilter, element: E, quirks_mode: QuirksMode)
+                                      where E: TElement<'a> {
+    bf.insert(element.get_local_name());
+    bf.insert(element.get_namespace());
+    let quirks_mode = quirks_mode == QuirksMode::Quirks;
+    match element.get_id() {
+        Some(id) => bf.insert(&if quirks_mode { to_ascii_lowercase_atom(&id) } else { id }),
+        None => {}
+    }
+    element.each_class(|class| {
+        if quirks_mode {
+            bf.insert(&to_ascii_lowercase_atom(class))
+        } else {
+            bf.insert(class)
+        }
+    });
+}
+
+/// Undoes `insert_into_bloom_filter`.
+pub fn remove_from_bloom_filter<'a,E>(bf: &mut BloomFilter, element: E, quirks_mode: QuirksMode)
+                                      where E: TElement<'a> {
+    bf.remove(element.get_local_name());
+    bf.remove(element.get_namespace());
+    let quirks_mode = quirks_mode == QuirksMode::Quirks;
+    match element.get_id() {
+        Some(id) => bf.remove(&if quirks_mode { to_ascii_lowercase_atom(&id) } else { id }),
+        None => {}
+    }
+    element.each_class(|class| {
+        if quirks_mode {
+            bf.remove(&to_ascii_lowercase_atom(class))
+        } else {
+            bf.remove(class)
+        }
+    });
+}
+
 
-pub struct Rule<T> {
-    // This is an Arc because Rule will essentially be cloned for every node
-    // that it matches. Selector contains an owned vector (through
-    // CompoundSelector) and we want to avoid the allocation.
-    pub selector: Arc<CompoundSelector>,
+pub struct Rule<T, Impl: SelectorImpl> {
+    // The components of the selector are already behind an Arc in `Selector`, so cloning a Rule
+    // does not copy them.
+    //
+    // For a selector with a pseudo-element, the Rule matches the originating element.
+    pub selector: Selector<Impl>,
     pub declarations: DeclarationBlock<T>,
 }
 
@@ -264,7 +340,7 @@ pub struct Rule<T> {
 pub struct DeclarationBlock<T> {
     pub declarations: Arc<T>,
     pub source_order: usize,
-    pub specificity: u32,
+    pub specificity: Specificity,
 }
 
 // FIXME(https://github.com/rust-lang/rust/issues/7671)
@@ -280,8 +356,8 @@ impl<T> Clone for DeclarationBlock<T> {
 }
 
 // FIXME(https://github.com/rust-lang/rust/issues/7671)
-impl<T> Clone for Rule<T> {
-    fn clone(&self) -> Rule<T> {
+impl<T, Impl: SelectorImpl> Clone for Rule<T, Impl> {
+    fn clone(&self) -> Rule<T, Impl> {
         Rule {
             selector: self.selector.clone(),
             declarations: self.declarations.clone(),
@@ -295,19 +371,164 @@ impl<T> DeclarationBlock<T> {
         DeclarationBlock {
             declarations: declarations,
             source_order: 0,
-            specificity: 0,
+            specificity: Specificity::default(),
+        }
+    }
+}
+
+/// The quirks mode of a document, as determined by its doctype.
+///
+/// https://dom.spec.whatwg.org/#concept-document-quirks
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    /// IDs and classes match ASCII case-insensitively.
+    Quirks,
+}
+
+/// State shared by all the selector matching done during one traversal of the tree.
+///
+/// It caches results that depend on the tree, keyed by `TNode::opaque` and by the addresses of
+/// the selectors they were computed for. `clear_caches` must be called once the tree changes, and
+/// before matching selectors that were not alive when the caches were last cleared: those could
+/// have been allocated where selectors that have since been dropped were. Changing one of the
+/// public fields clears the caches the next time they are used.
+pub struct MatchingContext {
+    /// The quirks mode of the document the tree belongs to. `NoQuirks` by default.
+    pub quirks_mode: QuirksMode,
+    /// The URL of the document, for `:target` and `:local-link`. Neither matches when it is
+    /// `None`.
+    pub document_url: Option<String>,
+    /// The `TNode::opaque` of the scoping root, for `:scope`. When it is `None`, `:scope` matches
+    /// the root element, like `:root`.
+    pub scope: Option<usize>,
+    /// The `TNode::opaque` of the shadow host when matching the rules of its shadow tree, for
+    /// `:host` and `:host-context()`. Neither matches when it is `None`.
+    pub shadow_host: Option<usize>,
+    /// Results of `:has()`, keyed by the element's `TNode::opaque` and the address of the list of
+    /// relative selectors.
+    has_cache: HashMap<(usize, usize), bool, DefaultState<FnvHasher>>,
+    /// Results of `has_relative_match`, keyed by the node's `TNode::opaque`, the address of the
+    /// relative selector and the index of the compound selector.
+    relative_match_cache: HashMap<(usize, usize, usize), bool, DefaultState<FnvHasher>>,
+    /// The `quirks_mode`, `document_url`, `scope` and `shadow_host` the caches were filled with.
+    cached_for: (QuirksMode, Option<String>, Option<usize>, Option<usize>),
+}
+
+impl MatchingContext {
+    pub fn new() -> MatchingContext {
+        MatchingContext {
+            quirks_mode: QuirksMode::NoQuirks,
+            document_url: None,
+            scope: None,
+            shadow_host: None,
+            has_cache: HashMap::with_hash_state(Default::default()),
+            relative_match_cache: HashMap::with_hash_state(Default::default()),
+            cached_for: (QuirksMode::NoQuirks, None, None, None),
+        }
+    }
+
+    pub fn clear_caches(&mut self) {
+        self.has_cache.clear();
+        self.relative_match_cache.clear();
+    }
+
+    /// Clears the caches if they were filled for other values of the public fields, which the
+    /// results depend on.
+    fn clear_caches_if_stale(&mut self) {
+        if self.cached_for.0 == self.quirks_mode &&
+           self.cached_for.1 == self.document_url &&
+           self.cached_for.2 == self.scope &&
+           self.cached_for.3 == self.shadow_host {
+            return
         }
+        self.clear_caches();
+        self.cached_for = (self.quirks_mode, self.document_url.clone(), self.scope,
+                           self.shadow_host);
     }
 }
 
-pub fn matches<'a,N>(selector_list: &Vec<Selector>,
-                     element: &N,
-                     parent_bf: &Option<Box<BloomFilter>>)
-                     -> bool
-                     where N: TNode<'a> {
+pub fn matches<'a,N,Impl>(selector_list: &Vec<Selector<Impl>>,
+                          element: &N,
+                          parent_bf: &Option<Box<BloomFilter>>,
+                          context: &mut MatchingContext)
+                          -> bool
+                          where N: TNode<'a>,
+                                N::Element: TElement<'a, Impl=Impl>,
+                                Impl: SelectorImpl {
     selector_list.iter().any(|selector| {
         selector.pseudo_element.is_none() &&
-        matches_compound_selector(&*selector.compound_selectors, element, parent_bf, &mut false)
+        matches_compound_selector(selector.complex_selector.rightmost_compound(), element,
+                                  parent_bf, context, &mut false)
+    })
+}
+
+/// Whether `element` is styled by `selector` through its `::slotted()` or `::part()`
+/// pseudo-element: whether it is assigned to a slot, or is a part of a shadow host, that matches
+/// `selector` as the originating element. The pseudo-classes after the pseudo-element, if any, are
+/// matched against `element`.
+///
+/// Parts are only exposed to the tree of their host: `exportparts` is not supported. Other
+/// pseudo-elements are not elements of the tree, and never match.
+pub fn matches_shadow_pseudo_element<'a,N,Impl>(selector: &Selector<Impl>,
+                                                element: &N,
+                                                context: &mut MatchingContext)
+                                                -> bool
+                                                where N: TNode<'a>,
+                                                      N::Element: TElement<'a, Impl=Impl>,
+                                                      Impl: SelectorImpl {
+    let mut shareable = false;
+    let originating_element = match selector.pseudo_element {
+        // https://drafts.csswg.org/css-scoping/#slotted-pseudo
+        Some(PseudoElement::Slotted(ref simple_selectors)) => {
+            if !simple_selectors.iter().all(|simple_selector| {
+                matches_simple_selector(simple_selector, element, context, &mut shareable)
+            }) {
+                return false
+            }
+            element.assigned_slot()
+        }
+        // https://drafts.csswg.org/css-shadow-parts/#part
+        Some(PseudoElement::Part(ref names)) => {
+            let part = element.as_element().get_attr(&ns!(""), &Atom::from_slice("part"));
+            match part {
+                Some(part) if names.iter().all(|name| {
+                    part.split(SELECTOR_WHITESPACE).any(|part_name| part_name == &**name)
+                }) => {}
+                _ => return false,
+            }
+            element.containing_shadow_host()
+        }
+        _ => return false,
+    };
+    match originating_element {
+        Some(originating_element) => {
+            matches_compound_selector(selector.complex_selector.rightmost_compound(),
+                                      &originating_element, &None, context, &mut shareable) &&
+            selector.pseudo_element_state.iter().all(|simple_selector| {
+                matches_simple_selector(simple_selector, element, context, &mut shareable)
+            })
+        }
+        None => false,
+    }
+}
+
+/// Like `matches_compound_selector`, but also checks the pseudo-classes after the pseudo-element,
+/// if any, against `element` as the originating element.
+fn matches_selector<'a,N,Impl>(selector: &Selector<Impl>,
+                               element: &N,
+                               parent_bf: &Option<Box<BloomFilter>>,
+                               context: &mut MatchingContext,
+                               shareable: &mut bool)
+                               -> bool
+                               where N: TNode<'a>,
+                                     N::Element: TElement<'a, Impl=Impl>,
+                                     Impl: SelectorImpl {
+    matches_compound_selector(selector.complex_selector.rightmost_compound(), element, parent_bf,
+                              context, shareable) &&
+    selector.pseudo_element_state.iter().all(|simple_selector| {
+        matches_simple_selector(simple_selector, element, context, shareable)
     })
 }
 
@@ -317,13 +538,16 @@ pub fn matches<'a,N>(selector_list: &Vec<Selector>,
 /// `shareable` to false unless you are willing to update the style sharing logic. Otherwise things
 /// will almost certainly break as nodes will start mistakenly sharing styles. (See the code in
 /// `main/css/matching.rs`.)
-fn matches_compound_selector<'a,N>(selector: &CompoundSelector,
-                                   element: &N,
-                                   parent_bf: &Option<Box<BloomFilter>>,
-                                   shareable: &mut bool)
-                                   -> bool
-                                   where N: TNode<'a> {
-    match matches_compound_selector_internal(selector, element, parent_bf, shareable) {
+fn matches_compound_selector<'a,N,Impl>(selector: CompoundSelector<Impl>,
+                                        element: &N,
+                                        parent_bf: &Option<Box<BloomFilter>>,
+                                        context: &mut MatchingContext,
+                                        shareable: &mut bool)
+                                        -> bool
+                                        where N: TNode<'a>,
+                                              N::Element: TElement<'a, Impl=Impl>,
+                                              Impl: SelectorImpl {
+    match matches_compound_selector_internal(selector, element, parent_bf, context, shareable) {
         SelectorMatchingResult::Matched => true,
         _ => false
     }
@@ -382,14 +606,19 @@ enum SelectorMatchingResult {
 /// Quickly figures out whether or not the compound selector is worth doing more
 /// work on. If the simple selectors don't match, or there's a child selector
 /// that does not appear in the bloom parent bloom filter, we can exit early.
-fn can_fast_reject<'a,N>(mut selector: &CompoundSelector,
-                         element: &N,
-                         parent_bf: &Option<Box<BloomFilter>>,
-                         shareable: &mut bool)
-                         -> Option<SelectorMatchingResult>
-                         where N: TNode<'a> {
-    if !selector.simple_selectors.iter().all(|simple_selector| {
-      matches_simple_selector(simple_selector, element, shareable) }) {
+///
+/// The bloom filter must have been filled with `insert_into_bloom_filter`.
+fn can_fast_reject<'a,N,Impl>(selector: CompoundSelector<Impl>,
+                              element: &N,
+                              parent_bf: &Option<Box<BloomFilter>>,
+                              context: &mut MatchingContext,
+                              shareable: &mut bool)
+                              -> Option<SelectorMatchingResult>
+                              where N: TNode<'a>,
+                                    N::Element: TElement<'a, Impl=Impl>,
+                                    Impl: SelectorImpl {
+    if !selector.simple_selectors().all(|simple_selector| {
+      matches_simple_selector(simple_selector, element, context, shareable) }) {
         return Some(SelectorMatchingResult::NotMatchedAndRestartFromClosestLaterSibling);
     }
 
@@ -400,17 +629,15 @@ fn can_fast_reject<'a,N>(mut selector: &CompoundSelector,
 
     // See if the bloom filter can exclude any of the descendant selectors, and
     // reject if we can.
-    loop {
-         match selector.next {
-             None => break,
-             Some((ref cs, Combinator::Descendant)) => selector = &**cs,
-             Some((ref cs, _)) => {
-                 selector = &**cs;
-                 continue;
-             }
-         };
-
-        for ss in selector.simple_selectors.iter() {
+    let mut descendant_combinator_to_the_right = false;
+    for (selector, combinator) in selector.compounds_right_to_left() {
+        let check = descendant_combinator_to_the_right;
+        descendant_combinator_to_the_right = combinator == Some(Combinator::Descendant);
+        if !check {
+            continue
+        }
+
+        for ss in selector.simple_selectors() {
             match *ss {
                 SimpleSelector::LocalName(LocalName { ref name, ref lower_name })  => {
                     if !bf.might_contain(name)
@@ -418,18 +645,19 @@ fn can_fast_reject<'a,N>(mut selector: &CompoundSelector,
                         return Some(SelectorMatchingResult::NotMatchedGlobally);
                     }
                 },
-                SimpleSelector::Namespace(ref namespace) => {
+                SimpleSelector::Namespace(_, ref namespace) => {
                     if !bf.might_contain(namespace) {
                         return Some(SelectorMatchingResult::NotMatchedGlobally);
                     }
                 },
-                SimpleSelector::ID(ref id) => {
-                    if !bf.might_contain(id) {
-                        return Some(SelectorMatchingResult::NotMatchedGlobally);
-                    }
-                },
-                SimpleSelector::Class(ref class) => {
-                    if !bf.might_contain(class) {
+                // In quirks mode the filter has IDs and classes ASCII-lowercased.
+                SimpleSelector::ID(ref name) | SimpleSelector::Class(ref name) => {
+                    let found = if context.quirks_mode == QuirksMode::Quirks {
+                        bf.might_contain(&to_ascii_lowercase_atom(name))
+                    } else {
+                        bf.might_contain(name)
+                    };
+                    if !found {
                         return Some(SelectorMatchingResult::NotMatchedGlobally);
                     }
                 },
@@ -443,73 +671,217 @@ fn can_fast_reject<'a,N>(mut selector: &CompoundSelector,
     return None;
 }
 
-fn matches_compound_selector_internal<'a,N>(selector: &CompoundSelector,
-                                            element: &N,
-                                            parent_bf: &Option<Box<BloomFilter>>,
-                                            shareable: &mut bool)
-                                            -> SelectorMatchingResult
-                                            where N: TNode<'a> {
-    match can_fast_reject(selector, element, parent_bf, shareable) {
+/// This does not recurse, so that selectors with many compound selectors can't overflow the
+/// stack: `searches` holds what would be a recursive call for each compound selector to the left
+/// of `selector` that is being matched.
+fn matches_compound_selector_internal<'a,N,Impl>(selector: CompoundSelector<Impl>,
+                                                 element: &N,
+                                                 parent_bf: &Option<Box<BloomFilter>>,
+                                                 context: &mut MatchingContext,
+                                                 shareable: &mut bool)
+                                                 -> SelectorMatchingResult
+                                                 where N: TNode<'a>,
+                                                       N::Element: TElement<'a, Impl=Impl>,
+                                                       Impl: SelectorImpl {
+    // The bloom filter only needs checking once: the ancestors of the candidates are ancestors
+    // of `element`.
+    match can_fast_reject(selector, element, parent_bf, context, shareable) {
         None => {},
         Some(result) => return result,
     };
 
-    match selector.next {
-        None => SelectorMatchingResult::Matched,
-        Some((ref next_selector, combinator)) => {
-            let (siblings, candidate_not_found) = match combinator {
-                Combinator::Child => (false, SelectorMatchingResult::NotMatchedGlobally),
-                Combinator::Descendant => (false, SelectorMatchingResult::NotMatchedGlobally),
-                Combinator::NextSibling => (true, SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant),
-                Combinator::LaterSibling => (true, SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant),
-            };
-            let mut node = (*element).clone();
-            loop {
-                let next_node = if siblings {
-                    node.prev_sibling()
+    let mut searches = vec!();
+    let shadow_host = context.shadow_host;
+    match start_candidate_search(selector, (*element).clone(), shadow_host, &mut searches) {
+        Some(result) => return result,
+        None => {}
+    }
+    loop {
+        let next_candidate = {
+            let search = searches.last_mut().unwrap();
+            let selector = search.selector;
+            search.next_candidate().map(|candidate| (selector, candidate))
+        };
+        let result = match next_candidate {
+            Ok((selector, candidate)) => {
+                if !selector.simple_selectors().all(|simple_selector| {
+                    matches_simple_selector(simple_selector, &candidate, context, shareable)
+                }) {
+                    SelectorMatchingResult::NotMatchedAndRestartFromClosestLaterSibling
                 } else {
-                    node.parent_node()
-                };
-                match next_node {
-                    None => return candidate_not_found,
-                    Some(next_node) => node = next_node,
+                    match start_candidate_search(selector, candidate, shadow_host,
+                                                 &mut searches) {
+                        Some(result) => result,
+                        None => continue,
+                    }
                 }
-                if node.is_element() {
-                    let result = matches_compound_selector_internal(&**next_selector,
-                                                                    &node,
-                                                                    parent_bf,
-                                                                    shareable);
-                    match (result, combinator) {
-                        // Return the status immediately.
-                        (SelectorMatchingResult::Matched, _) => return result,
-                        (SelectorMatchingResult::NotMatchedGlobally, _) => return result,
-
-                        // Upgrade the failure status to
-                        // NotMatchedAndRestartFromClosestDescendant.
-                        (_, Combinator::Child) => return SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant,
-
-                        // Return the status directly.
-                        (_, Combinator::NextSibling) => return result,
-
-                        // If the failure status is NotMatchedAndRestartFromClosestDescendant
-                        // and combinator is Combinator::LaterSibling, give up this Combinator::LaterSibling matching
-                        // and restart from the closest descendant combinator.
-                        (SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant, Combinator::LaterSibling) => return result,
-
-                        // The Combinator::Descendant combinator and the status is
-                        // NotMatchedAndRestartFromClosestLaterSibling or
-                        // NotMatchedAndRestartFromClosestDescendant,
-                        // or the Combinator::LaterSibling combinator and the status is
-                        // NotMatchedAndRestartFromClosestDescendant
-                        // can continue to matching on the next candidate element.
-                        _ => {},
+            }
+            Err(result) => {
+                match end_candidate_search(&mut searches, result) {
+                    Some(result) => return result,
+                    None => continue,
+                }
+            }
+        };
+        match searches.last().unwrap().result_after(result) {
+            Some(result) => {
+                match end_candidate_search(&mut searches, result) {
+                    Some(result) => return result,
+                    None => {}
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+/// The search for elements that match `selector` among those that relate to `node` through
+/// `combinator`, where `node` is an element that matches the compound selector to the right of
+/// `selector`.
+///
+/// This is what a call of a recursive `matches_compound_selector_internal` would be doing.
+struct CandidateSearch<'s, N, Impl: SelectorImpl + 's> {
+    selector: CompoundSelector<'s, Impl>,
+    combinator: Combinator,
+    /// The last candidate, starting with the element the search started from.
+    node: N,
+    /// Whether `node` is the shadow host of the tree whose rules are being matched, which has no
+    /// parent or siblings for them.
+    at_shadow_host: bool,
+}
+
+impl<'s, N, Impl: SelectorImpl> CandidateSearch<'s, N, Impl> {
+    /// Moves to the next candidate element, or returns the result of the search if there is none.
+    fn next_candidate<'a>(&mut self) -> Result<N, SelectorMatchingResult> where N: TNode<'a> {
+        let (siblings, candidate_not_found) = match self.combinator {
+            Combinator::Child => (false, SelectorMatchingResult::NotMatchedGlobally),
+            Combinator::Descendant => (false, SelectorMatchingResult::NotMatchedGlobally),
+            Combinator::NextSibling => (true, SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant),
+            Combinator::LaterSibling => (true, SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant),
+        };
+        loop {
+            if self.at_shadow_host {
+                // The host has no parent or siblings that the shadow tree can see.
+                return Err(candidate_not_found)
+            }
+            let next_node = if siblings {
+                self.node.prev_sibling()
+            } else {
+                self.node.parent_node()
+            };
+            match next_node {
+                None => return Err(candidate_not_found),
+                Some(next_node) => self.node = next_node,
+            }
+            if !siblings {
+                match self.node.shadow_root_host() {
+                    // The host acts as the parent of the top-level elements of its shadow
+                    // tree, but only for `:host`: it is featureless otherwise.
+                    Some(host) if is_host_compound_selector(self.selector) => {
+                        self.node = host;
+                        self.at_shadow_host = true;
                     }
+                    Some(_) => return Err(candidate_not_found),
+                    None => {}
+                }
+            }
+            if self.node.is_element() {
+                return Ok(self.node.clone())
+            }
+        }
+    }
+
+    /// The result of the search once a candidate gave `result`, or `None` to go on with the next
+    /// candidate.
+    fn result_after(&self, result: SelectorMatchingResult) -> Option<SelectorMatchingResult> {
+        match (result, self.combinator) {
+            // Return the status immediately.
+            (SelectorMatchingResult::Matched, _) => Some(result),
+            (SelectorMatchingResult::NotMatchedGlobally, _) => Some(result),
+
+            // Upgrade the failure status to
+            // NotMatchedAndRestartFromClosestDescendant.
+            (_, Combinator::Child) => Some(SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant),
+
+            // Return the status directly.
+            (_, Combinator::NextSibling) => Some(result),
+
+            // If the failure status is NotMatchedAndRestartFromClosestDescendant
+            // and combinator is Combinator::LaterSibling, give up this Combinator::LaterSibling matching
+            // and restart from the closest descendant combinator.
+            (SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant, Combinator::LaterSibling) => Some(result),
+
+            // The Combinator::Descendant combinator and the status is
+            // NotMatchedAndRestartFromClosestLaterSibling or
+            // NotMatchedAndRestartFromClosestDescendant,
+            // or the Combinator::LaterSibling combinator and the status is
+            // NotMatchedAndRestartFromClosestDescendant
+            // can continue to matching on the next candidate element.
+            _ => None,
+        }
+    }
+}
+
+/// Called when `element` matches the simple selectors of `selector`. Pushes the search for the
+/// compound selector to the left of `selector` onto `searches`, or returns the result if there
+/// is none. `shadow_host` is that of the `MatchingContext`.
+fn start_candidate_search<'a,'s,N,Impl>(selector: CompoundSelector<'s, Impl>,
+                                        element: N,
+                                        shadow_host: Option<usize>,
+                                        searches: &mut Vec<CandidateSearch<'s, N, Impl>>)
+                                        -> Option<SelectorMatchingResult>
+                                        where N: TNode<'a>,
+                                              Impl: SelectorImpl {
+    match selector.next() {
+        None => Some(SelectorMatchingResult::Matched),
+        Some((next_selector, combinator)) => {
+            let at_shadow_host = shadow_host == Some(element.opaque());
+            searches.push(CandidateSearch {
+                selector: next_selector,
+                combinator: combinator,
+                node: element,
+                at_shadow_host: at_shadow_host,
+            });
+            None
+        }
+    }
+}
+
+/// Pops the innermost search, which gave `result`, and the searches that end with it. Returns
+/// the result of the outermost one if it ended, `None` if a search goes on with its next
+/// candidate.
+fn end_candidate_search<'s,N,Impl>(searches: &mut Vec<CandidateSearch<'s, N, Impl>>,
+                                   mut result: SelectorMatchingResult)
+                                   -> Option<SelectorMatchingResult>
+                                   where Impl: SelectorImpl {
+    loop {
+        searches.pop();
+        match searches.last() {
+            None => return Some(result),
+            Some(search) => {
+                match search.result_after(result) {
+                    Some(search_result) => result = search_result,
+                    None => return None,
                 }
             }
         }
     }
 }
 
+/// Whether `selector` has a `:host`, `:host()` or `:host-context()` and no other simple
+/// selectors, so that it can match a shadow host from within its shadow tree.
+fn is_host_compound_selector<Impl: SelectorImpl>(selector: CompoundSelector<Impl>) -> bool {
+    // Not `all`, which is true for `*`.
+    let mut has_host = false;
+    for simple_selector in selector.simple_selectors() {
+        match *simple_selector {
+            SimpleSelector::Host(..) | SimpleSelector::HostContext(..) => has_host = true,
+            _ => return false,
+        }
+    }
+    has_host
+}
+
 bitflags! {
     flags CommonStyleAffectingAttributes: u8 {
         const HIDDEN_ATTRIBUTE = 0x01,
@@ -572,11 +944,14 @@ pub fn rare_style_affecting_attributes() -> [Atom; 3] {
 /// will almost certainly break as nodes will start mistakenly sharing styles. (See the code in
 /// `main/css/matching.rs`.)
 #[inline]
-pub fn matches_simple_selector<'a,N>(selector: &SimpleSelector,
-                                     element: &N,
-                                     shareable: &mut bool)
-                                     -> bool
-                                     where N: TNode<'a> {
+pub fn matches_simple_selector<'a,N,Impl>(selector: &SimpleSelector<Impl>,
+                                          element: &N,
+                                          context: &mut MatchingContext,
+                                          shareable: &mut bool)
+                                          -> bool
+                                          where N: TNode<'a>,
+                                                N::Element: TElement<'a, Impl=Impl>,
+                                                Impl: SelectorImpl {
     match *selector {
         SimpleSelector::LocalName(LocalName { ref name, ref lower_name }) => {
             let name = if element.is_html_element_in_html_document() { lower_name } else { name };
@@ -584,21 +959,33 @@ pub fn matches_simple_selector<'a,N>(selector: &SimpleSelector,
             element.get_local_name() == name
         }
 
-        SimpleSelector::Namespace(ref namespace) => {
+        SimpleSelector::Namespace(_, ref namespace) => {
             let element = element.as_element();
             element.get_namespace() == namespace
         }
-        // TODO: case-sensitivity depends on the document type and quirks mode
+        SimpleSelector::ExplicitAnyNamespace => true,
         SimpleSelector::ID(ref id) => {
             *shareable = false;
             let element = element.as_element();
             element.get_id().map_or(false, |attr| {
-                attr == *id
+                if context.quirks_mode == QuirksMode::Quirks {
+                    attr.eq_ignore_ascii_case(id)
+                } else {
+                    attr == *id
+                }
             })
         }
         SimpleSelector::Class(ref class) => {
             let element = element.as_element();
-            element.has_class(class)
+            if context.quirks_mode == QuirksMode::Quirks {
+                let mut has_class = false;
+                element.each_class(|element_class| {
+                    has_class = has_class || element_class.eq_ignore_ascii_case(class)
+                });
+                has_class
+            } else {
+                element.has_class(class)
+            }
         }
 
         SimpleSelector::AttrExists(ref attr) => {
@@ -626,42 +1013,67 @@ pub fn matches_simple_selector<'a,N>(selector: &SimpleSelector,
                 // here because the UA style otherwise disables all style sharing completely.
                 *shareable = false
             }
-            element.match_attr(attr, |attr_value| {
-                match case_sensitivity {
-                    CaseSensitivity::CaseSensitive => attr_value == *value,
-                    CaseSensitivity::CaseInsensitive => attr_value.eq_ignore_ascii_case(value),
-                }
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                bytes_eq(attr_value.as_bytes(), value.as_bytes(), ignore_case)
             })
         }
-        SimpleSelector::AttrIncludes(ref attr, ref value) => {
+        // https://drafts.csswg.org/selectors/#attribute-representation
+        SimpleSelector::AttrIncludes(ref attr, ref value, case_sensitivity) => {
             *shareable = false;
-            element.match_attr(attr, |attr_value| {
-                attr_value.split(SELECTOR_WHITESPACE).any(|v| v == *value)
+            if value.is_empty() || value.contains(SELECTOR_WHITESPACE) {
+                return false
+            }
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                attr_value.split(SELECTOR_WHITESPACE).any(|v| {
+                    bytes_eq(v.as_bytes(), value.as_bytes(), ignore_case)
+                })
             })
         }
-        SimpleSelector::AttrDashMatch(ref attr, ref value, ref dashing_value) => {
+        SimpleSelector::AttrDashMatch(ref attr, ref value, case_sensitivity) => {
             *shareable = false;
-            element.match_attr(attr, |attr_value| {
-                attr_value == *value ||
-                attr_value.starts_with(dashing_value)
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                let (attr_value, value) = (attr_value.as_bytes(), value.as_bytes());
+                attr_value.len() >= value.len() &&
+                bytes_eq(&attr_value[..value.len()], value, ignore_case) &&
+                (attr_value.len() == value.len() || attr_value[value.len()] == b'-')
             })
         }
-        SimpleSelector::AttrPrefixMatch(ref attr, ref value) => {
+        // https://drafts.csswg.org/selectors/#attribute-substrings
+        SimpleSelector::AttrPrefixMatch(ref attr, ref value, case_sensitivity) => {
             *shareable = false;
-            element.match_attr(attr, |attr_value| {
-                attr_value.starts_with(value)
+            if value.is_empty() {
+                return false
+            }
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                let (attr_value, value) = (attr_value.as_bytes(), value.as_bytes());
+                attr_value.len() >= value.len() &&
+                bytes_eq(&attr_value[..value.len()], value, ignore_case)
             })
         }
-        SimpleSelector::AttrSubstringMatch(ref attr, ref value) => {
+        SimpleSelector::AttrSubstringMatch(ref attr, ref value, case_sensitivity) => {
             *shareable = false;
-            element.match_attr(attr, |attr_value| {
-                attr_value.contains(value)
+            if value.is_empty() {
+                return false
+            }
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                if ignore_case {
+                    attr_value.as_bytes().windows(value.len()).any(|window| {
+                        window.eq_ignore_ascii_case(value.as_bytes())
+                    })
+                } else {
+                    attr_value.contains(&**value)
+                }
             })
         }
-        SimpleSelector::AttrSuffixMatch(ref attr, ref value) => {
+        SimpleSelector::AttrSuffixMatch(ref attr, ref value, case_sensitivity) => {
             *shareable = false;
-            element.match_attr(attr, |attr_value| {
-                attr_value.ends_with(value)
+            if value.is_empty() {
+                return false
+            }
+            match_attr_value(element, attr, case_sensitivity, |attr_value, ignore_case| {
+                let (attr_value, value) = (attr_value.as_bytes(), value.as_bytes());
+                attr_value.len() >= value.len() &&
+                bytes_eq(&attr_value[attr_value.len() - value.len()..], value, ignore_case)
             })
         }
 
@@ -686,41 +1098,131 @@ pub fn matches_simple_selector<'a,N>(selector: &SimpleSelector,
                 None => false,
             }
         }
+        // https://drafts.csswg.org/selectors-4/#local-link-pseudo
+        SimpleSelector::LocalLink => {
+            *shareable = false;
+            let elem = element.as_element();
+            match (elem.get_link(), context.document_url.as_ref()) {
+                (Some(url), Some(document_url)) => {
+                    strip_fragment(url) == strip_fragment(document_url)
+                }
+                _ => false,
+            }
+        }
+        // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-target
+        SimpleSelector::Target => {
+            *shareable = false;
+            let fragment = match context.document_url {
+                Some(ref document_url) => match document_url.find('#') {
+                    Some(index) => &document_url[index + 1..],
+                    None => return false,
+                },
+                None => return false,
+            };
+            if fragment.is_empty() {
+                return false
+            }
+            // The indicated part is the element whose ID is the fragment, or failing that the
+            // percent-decoded fragment. Elements with either ID match, as looking for the first
+            // would need the whole document.
+            match element.as_element().get_id() {
+                Some(id) => &*id == fragment || *id == *percent_decode(fragment),
+                None => false,
+            }
+        }
+        // https://drafts.csswg.org/selectors-4/#scope-pseudo
+        SimpleSelector::Scope => {
+            *shareable = false;
+            match context.scope {
+                Some(scope) => element.opaque() == scope,
+                None => matches_root(element),
+            }
+        }
         // https://html.spec.whatwg.org/multipage/scripting.html#selector-hover
         SimpleSelector::Hover => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_hover_state()
+            element.as_element().get_state().contains(IN_HOVER_STATE)
         },
         // https://html.spec.whatwg.org/multipage/scripting.html#selector-focus
         SimpleSelector::Focus => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_focus_state()
+            element.as_element().get_state().contains(IN_FOCUS_STATE)
+        },
+        // https://drafts.csswg.org/selectors-4/#focus-within-pseudo
+        SimpleSelector::FocusWithin => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_FOCUS_WITHIN_STATE)
+        },
+        // https://drafts.csswg.org/selectors-4/#focus-visible-pseudo
+        SimpleSelector::FocusVisible => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_FOCUS_VISIBLE_STATE)
+        },
+        // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-active
+        SimpleSelector::Active => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_ACTIVE_STATE)
         },
         // http://www.whatwg.org/html/#selector-disabled
         SimpleSelector::Disabled => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_disabled_state()
+            element.as_element().get_state().contains(IN_DISABLED_STATE)
         },
         // http://www.whatwg.org/html/#selector-enabled
         SimpleSelector::Enabled => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_enabled_state()
+            element.as_element().get_state().contains(IN_ENABLED_STATE)
         },
         // https://html.spec.whatwg.org/multipage/scripting.html#selector-checked
         SimpleSelector::Checked => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_checked_state()
+            element.as_element().get_state().contains(IN_CHECKED_STATE)
         }
         // https://html.spec.whatwg.org/multipage/scripting.html#selector-indeterminate
         SimpleSelector::Indeterminate => {
             *shareable = false;
-            let elem = element.as_element();
-            elem.get_indeterminate_state()
+            element.as_element().get_state().contains(IN_INDETERMINATE_STATE)
+        }
+        // https://html.spec.whatwg.org/multipage/semantics-other.html#pseudo-classes
+        SimpleSelector::Required => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_REQUIRED_STATE)
+        }
+        SimpleSelector::Optional => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_OPTIONAL_STATE)
+        }
+        SimpleSelector::Valid => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_VALID_STATE)
+        }
+        SimpleSelector::Invalid => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_INVALID_STATE)
+        }
+        SimpleSelector::ReadOnly => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_READ_ONLY_STATE)
+        }
+        SimpleSelector::ReadWrite => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_READ_WRITE_STATE)
+        }
+        SimpleSelector::PlaceholderShown => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_PLACEHOLDER_SHOWN_STATE)
+        }
+        SimpleSelector::Default => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_DEFAULT_STATE)
+        }
+        SimpleSelector::InRange => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_IN_RANGE_STATE)
+        }
+        SimpleSelector::OutOfRange => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_OUT_OF_RANGE_STATE)
         }
         SimpleSelector::FirstChild => {
             *shareable = false;
@@ -740,48 +1242,173 @@ pub fn matches_simple_selector<'a,N>(selector: &SimpleSelector,
             matches_root(element)
         }
 
+        SimpleSelector::Empty => {
+            *shareable = false;
+            matches_empty(element)
+        }
+
+        // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-defined
+        SimpleSelector::Defined => {
+            *shareable = false;
+            element.as_element().get_state().contains(IN_DEFINED_STATE)
+        }
+        // https://html.spec.whatwg.org/multipage/semantics-other.html#selector-custom
+        SimpleSelector::State(ref state) => {
+            *shareable = false;
+            element.as_element().has_custom_state(state)
+        }
+
         SimpleSelector::NthChild(a, b) => {
             *shareable = false;
-            matches_generic_nth_child(element, a, b, false, false)
+            matches_generic_nth_child(element, a, b, false, false, None, context, shareable)
         }
         SimpleSelector::NthLastChild(a, b) => {
             *shareable = false;
-            matches_generic_nth_child(element, a, b, false, true)
+            matches_generic_nth_child(element, a, b, false, true, None, context, shareable)
+        }
+        SimpleSelector::NthChildOf(a, b, ref selectors) => {
+            *shareable = false;
+            matches_generic_nth_child(element, a, b, false, false, Some(&**selectors), context,
+                                      shareable)
+        }
+        SimpleSelector::NthLastChildOf(a, b, ref selectors) => {
+            *shareable = false;
+            matches_generic_nth_child(element, a, b, false, true, Some(&**selectors), context,
+                                      shareable)
         }
         SimpleSelector::NthOfType(a, b) => {
             *shareable = false;
-            matches_generic_nth_child(element, a, b, true, false)
+            matches_generic_nth_child(element, a, b, true, false, None, context, shareable)
         }
         SimpleSelector::NthLastOfType(a, b) => {
             *shareable = false;
-            matches_generic_nth_child(element, a, b, true, true)
+            matches_generic_nth_child(element, a, b, true, true, None, context, shareable)
         }
 
         SimpleSelector::FirstOfType => {
             *shareable = false;
-            matches_generic_nth_child(element, 0, 1, true, false)
+            matches_generic_nth_child(element, 0, 1, true, false, None, context, shareable)
         }
         SimpleSelector::LastOfType => {
             *shareable = false;
-            matches_generic_nth_child(element, 0, 1, true, true)
+            matches_generic_nth_child(element, 0, 1, true, true, None, context, shareable)
         }
         SimpleSelector::OnlyOfType => {
             *shareable = false;
-            matches_generic_nth_child(element, 0, 1, true, false) &&
-                matches_generic_nth_child(element, 0, 1, true, true)
+            matches_generic_nth_child(element, 0, 1, true, false, None, context, shareable) &&
+                matches_generic_nth_child(element, 0, 1, true, true, None, context, shareable)
+        }
+
+        SimpleSelector::Lang(ref ranges) => {
+            *shareable = false;
+            match element.get_lang() {
+                Some(lang) => ranges.iter().any(|range| lang_matches(range, lang)),
+                None => false,
+            }
+        }
+        SimpleSelector::Dir(direction) => {
+            *shareable = false;
+            element.get_direction() == direction
+        }
+
+        // https://drafts.csswg.org/css-scoping/#host-selector
+        SimpleSelector::Host(ref simple_selectors) => {
+            *shareable = false;
+            if context.shadow_host != Some(element.opaque()) {
+                return false
+            }
+            match *simple_selectors {
+                Some(ref simple_selectors) => simple_selectors.iter().all(|simple_selector| {
+                    matches_simple_selector(simple_selector, element, context, shareable)
+                }),
+                None => true,
+            }
+        }
+        SimpleSelector::HostContext(ref simple_selectors) => {
+            *shareable = false;
+            if context.shadow_host != Some(element.opaque()) {
+                return false
+            }
+            // The host or one of its shadow-including ancestors.
+            let mut node = element.clone();
+            loop {
+                if node.is_element() && simple_selectors.iter().all(|simple_selector| {
+                    matches_simple_selector(simple_selector, &node, context, shareable)
+                }) {
+                    return true
+                }
+                node = match node.parent_node() {
+                    Some(parent) => parent.shadow_root_host().unwrap_or(parent),
+                    None => return false,
+                }
+            }
         }
 
-        SimpleSelector::ServoNonzeroBorder => {
+        SimpleSelector::NonTSPseudoClass(ref pseudo_class) => {
             *shareable = false;
             let elem = element.as_element();
-            elem.has_nonzero_border()
+            elem.match_non_ts_pseudo_class(pseudo_class)
         }
 
-        SimpleSelector::Negation(ref negated) => {
+        SimpleSelector::Negation(ref selectors) => {
+            *shareable = false;
+            !matches_any_selector(selectors, element, context, shareable)
+        },
+        SimpleSelector::Is(ref selectors) | SimpleSelector::Where(ref selectors) => {
             *shareable = false;
-            !negated.iter().all(|s| matches_simple_selector(s, element, shareable))
+            matches_any_selector(selectors, element, context, shareable)
         },
+        SimpleSelector::Has(ref relative_selectors) => {
+            *shareable = false;
+            matches_has(relative_selectors, element, context, shareable)
+        },
+    }
+}
+
+/// The URL without its fragment, if any.
+#[inline]
+fn strip_fragment(url: &str) -> &str {
+    match url.find('#') {
+        Some(index) => &url[..index],
+        None => url,
+    }
+}
+
+/// https://url.spec.whatwg.org/#string-percent-decode, with invalid UTF-8 replaced.
+fn percent_decode(string: &str) -> String {
+    fn hex_value(byte: u8) -> Option<u8> {
+        match byte {
+            b'0'...b'9' => Some(byte - b'0'),
+            b'a'...b'f' => Some(byte - b'a' + 10),
+            b'A'...b'F' => Some(byte - b'A' + 10),
+            _ => None,
+        }
+    }
+
+    let bytes = string.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                (Some(high), Some(low)) => Some(high * 16 + low),
+                _ => None,
+            }
+        } else {
+            None
+        };
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1
+            }
+        }
     }
+    String::from_utf8_lossy(&decoded).into_owned()
 }
 
 #[inline]
@@ -793,35 +1420,429 @@ fn url_is_visited(_url: &str) -> bool {
     false
 }
 
+/// Does not intern a new atom if `atom` has no uppercase ASCII letters.
 #[inline]
-fn matches_generic_nth_child<'a,N>(element: &N,
-                                   a: i32,
-                                   b: i32,
-                                   is_of_type: bool,
-                                   is_from_end: bool)
-                                   -> bool
-                                   where N: TNode<'a> {
-    let mut node = element.clone();
-    // fail if we can't find a parent or if the node is the root element
-    // of the document (Cf. Selectors Level 3)
-    match node.parent_node() {
-        Some(parent) => if parent.is_document() {
-            return false;
-        },
-        None => return false
-    };
+fn to_ascii_lowercase_atom(atom: &Atom) -> Atom {
+    if atom.bytes().any(|byte| byte >= b'A' && byte <= b'Z') {
+        Atom::from_slice(&atom.to_ascii_lowercase())
+    } else {
+        atom.clone()
+    }
+}
 
-    let mut index = 1;
-    loop {
-        if is_from_end {
-            match node.next_sibling() {
-                None => break,
-                Some(next_sibling) => node = next_sibling
-            }
-        } else {
-            match node.prev_sibling() {
-                None => break,
-                Some(prev_sibling) => node = prev_sibling
+/// Whether `test` accepts the value of an attribute matching `attr`, along with whether that
+/// value compares ASCII case-insensitively given the flags of the selector.
+///
+/// Without a flag, that depends on the attribute itself rather than on the namespace constraint
+/// of the selector: `[*|type=checkbox]` ignores case for `type` in the null namespace, but not
+/// for `type` in other namespaces.
+#[inline]
+fn match_attr_value<'a,N,F>(element: &N,
+                            attr: &AttrSelector,
+                            case_sensitivity: CaseSensitivity,
+                            test: F)
+                            -> bool
+                            where N: TNode<'a>,
+                                  F: Fn(&str, bool) -> bool {
+    match case_sensitivity {
+        CaseSensitivity::CaseInsensitive => element.match_attr(attr, |value| test(value, true)),
+        CaseSensitivity::CaseSensitive => element.match_attr(attr, |value| test(value, false)),
+        // Selectors spec says language-defined, and HTML says sensitive except for the legacy
+        // attributes below.
+        CaseSensitivity::DocumentDefault => {
+            if !element.is_html_element_in_html_document() ||
+               HTML_CASE_INSENSITIVE_ATTRIBUTES.binary_search(&&*attr.lower_name).is_err() {
+                return element.match_attr(attr, |value| test(value, false))
+            }
+            match attr.namespace {
+                NamespaceConstraint::Specific(ref namespace) => {
+                    let ignore_case = *namespace == ns!("");
+                    element.match_attr(attr, |value| test(value, ignore_case))
+                }
+                // A value that matches case-sensitively also matches case-insensitively, so
+                // testing the attributes in other namespaces again with the one in the null
+                // namespace is harmless.
+                NamespaceConstraint::Any => {
+                    let null_namespace = AttrSelector {
+                        name: attr.name.clone(),
+                        lower_name: attr.lower_name.clone(),
+                        namespace: NamespaceConstraint::Specific(ns!("")),
+                        prefix: None,
+                    };
+                    element.match_attr(&null_namespace, |value| test(value, true)) ||
+                    element.match_attr(attr, |value| test(value, false))
+                }
+            }
+        }
+    }
+}
+
+/// Attributes in the null namespace whose values match ASCII case-insensitively on HTML elements
+/// in HTML documents, unless the selector has the `s` flag. Sorted, for `binary_search`.
+///
+/// https://html.spec.whatwg.org/multipage/semantics-other.html#case-sensitivity-of-selectors
+static HTML_CASE_INSENSITIVE_ATTRIBUTES: &'static [&'static str] = &[
+    "accept", "accept-charset", "align", "alink", "axis", "bgcolor", "charset", "checked",
+    "clear", "codetype", "color", "compact", "declare", "defer", "dir", "direction", "disabled",
+    "enctype", "face", "frame", "hreflang", "http-equiv", "lang", "language", "link", "media",
+    "method", "multiple", "nohref", "noresize", "noshade", "nowrap", "readonly", "rel", "rev",
+    "rules", "scope", "scrolling", "selected", "shape", "target", "text", "type", "valign",
+    "valuetype", "vlink",
+];
+
+/// Compares bytes rather than chars, so that slicing at a byte offset is enough for the substring
+/// operators.
+#[inline]
+fn bytes_eq(a: &[u8], b: &[u8], ignore_ascii_case: bool) -> bool {
+    if ignore_ascii_case { a.eq_ignore_ascii_case(b) } else { a == b }
+}
+
+/// Whether the language tag `lang` matches the language range `range`, per the extended filtering
+/// of RFC 4647 § 3.3.2: `de-*-DE` and `de-DE` both match `de-Latn-DE`.
+fn lang_matches(range: &str, lang: &str) -> bool {
+    let mut range_subtags = range.split('-');
+    let mut lang_subtags = lang.split('-');
+    // `split` always yields at least one item.
+    let first_range_subtag = range_subtags.next().unwrap();
+    let first_lang_subtag = lang_subtags.next().unwrap();
+    if first_range_subtag != "*" && !first_range_subtag.eq_ignore_ascii_case(first_lang_subtag) {
+        return false
+    }
+    let mut lang_subtag = lang_subtags.next();
+    for range_subtag in range_subtags {
+        if range_subtag == "*" {
+            continue
+        }
+        loop {
+            match lang_subtag {
+                None => return false,
+                Some(subtag) if subtag.eq_ignore_ascii_case(range_subtag) => {
+                    lang_subtag = lang_subtags.next();
+                    break
+                }
+                // Singletons introduce extensions, that the range does not reach into.
+                Some(subtag) if subtag.len() == 1 => return false,
+                Some(_) => lang_subtag = lang_subtags.next(),
+            }
+        }
+    }
+    true
+}
+
+/// Whether the element matches any selector of a list that is the argument of a pseudo-class.
+fn matches_any_selector<'a,N,Impl>(selectors: &[Selector<Impl>],
+                                   element: &N,
+                                   context: &mut MatchingContext,
+                                   shareable: &mut bool)
+                                   -> bool
+                                   where N: TNode<'a>,
+                                         N::Element: TElement<'a, Impl=Impl>,
+                                         Impl: SelectorImpl {
+    selectors.iter().any(|selector| {
+        matches_compound_selector(selector.complex_selector.rightmost_compound(), element, &None,
+                                  context, shareable)
+    })
+}
+
+/// https://drafts.csswg.org/selectors-4/#relational
+///
+/// The bloom filter is of no use here: it holds the ancestors of `element`, which the argument
+/// never reaches since it is matched against the descendants and later siblings of `element`.
+/// Matching the argument from right to left in the subtree, with a filter of the ancestors within
+/// it, would let the filter reject candidates, but the results would depend on where `:has()` is
+/// anchored and could not be cached per node as `has_relative_match` does, which made matching
+/// against each ancestor of a deep subtree quadratic.
+fn matches_has<'a,N,Impl>(relative_selectors: &[RelativeSelector<Impl>],
+                          element: &N,
+                          context: &mut MatchingContext,
+                          shareable: &mut bool)
+                          -> bool
+                          where N: TNode<'a>,
+                                N::Element: TElement<'a, Impl=Impl>,
+                                Impl: SelectorImpl {
+    context.clear_caches_if_stale();
+    let key = (element.opaque(), relative_selectors.as_ptr() as usize);
+    match context.has_cache.get(&key) {
+        Some(&result) => return result,
+        None => {}
+    }
+    let result = relative_selectors.iter().any(|relative_selector| {
+        matches_relative_selector(relative_selector, element, context, shareable)
+    });
+    context.has_cache.insert(key, result);
+    result
+}
+
+fn matches_relative_selector<'a,N,Impl>(relative_selector: &RelativeSelector<Impl>,
+                                        element: &N,
+                                        context: &mut MatchingContext,
+                                        shareable: &mut bool)
+                                        -> bool
+                                        where N: TNode<'a>,
+                                              N::Element: TElement<'a, Impl=Impl>,
+                                              Impl: SelectorImpl {
+    let selector = &relative_selector.selector;
+    let compounds = selector.compounds_left_to_right().map(|(compound, combinator)| {
+        (compound, combinator.unwrap_or(relative_selector.combinator))
+    }).collect::<Vec<_>>();
+    let relative_selector_key = relative_selector as *const RelativeSelector<Impl> as usize;
+    has_relative_match(&compounds, 0, relative_selector_key, element, context, shareable)
+}
+
+/// Whether an element that relates to `node` through the combinator of `compounds[index]`
+/// matches the compound selectors of `compounds[index..]`, each related to the previous one
+/// through its combinator. `compounds` is the argument of `:has()` from left to right, each
+/// compound selector with the combinator to its left, that of the leftmost one being the one the
+/// relative selector starts with.
+///
+/// This does not depend on where the relative selector is anchored, so it is cached for each node
+/// and each compound selector: matching `:has(.a .b)` against every element of a document visits
+/// each node once per compound selector. The recursion is bounded by the number of compound
+/// selectors.
+fn has_relative_match<'a,'s,N,Impl>(compounds: &[(CompoundSelector<'s, Impl>, Combinator)],
+                                    index: usize,
+                                    relative_selector_key: usize,
+                                    node: &N,
+                                    context: &mut MatchingContext,
+                                    shareable: &mut bool)
+                                    -> bool
+                                    where N: TNode<'a>,
+                                          N::Element: TElement<'a, Impl=Impl>,
+                                          Impl: SelectorImpl {
+    match compounds[index].1 {
+        Combinator::Child => {
+            let mut child = node.first_child();
+            while let Some(child_node) = child {
+                if child_node.is_element() &&
+                        starts_relative_match(compounds, index, relative_selector_key, &child_node,
+                                              context, shareable) {
+                    return true
+                }
+                child = child_node.next_sibling();
+            }
+            false
+        }
+        Combinator::NextSibling => {
+            let mut sibling = node.next_sibling();
+            while let Some(sibling_node) = sibling {
+                if sibling_node.is_element() {
+                    return starts_relative_match(compounds, index, relative_selector_key,
+                                                 &sibling_node, context, shareable)
+                }
+                sibling = sibling_node.next_sibling();
+            }
+            false
+        }
+        Combinator::Descendant => {
+            has_descendant_match(compounds, index, relative_selector_key, node, context, shareable)
+        }
+        Combinator::LaterSibling => {
+            has_later_sibling_match(compounds, index, relative_selector_key, node, context,
+                                    shareable)
+        }
+    }
+}
+
+/// Whether `element` matches `compounds[index]`, and starts a match of the compound selectors to
+/// its right.
+fn starts_relative_match<'a,'s,N,Impl>(compounds: &[(CompoundSelector<'s, Impl>, Combinator)],
+                                       index: usize,
+                                       relative_selector_key: usize,
+                                       element: &N,
+                                       context: &mut MatchingContext,
+                                       shareable: &mut bool)
+                                       -> bool
+                                       where N: TNode<'a>,
+                                             N::Element: TElement<'a, Impl=Impl>,
+                                             Impl: SelectorImpl {
+    let matches_compound = compounds[index].0.simple_selectors().all(|simple_selector| {
+        matches_simple_selector(simple_selector, element, context, shareable)
+    });
+    matches_compound &&
+    (index + 1 == compounds.len() ||
+     has_relative_match(compounds, index + 1, relative_selector_key, element, context, shareable))
+}
+
+/// `has_relative_match` for a descendant combinator.
+///
+/// The result is cached for every node whose subtree gets fully explored, and for the ancestors of
+/// a matching element up to `root`. Matching against each of the ancestors of a deep subtree thus
+/// visits every node at most once.
+fn has_descendant_match<'a,'s,N,Impl>(compounds: &[(CompoundSelector<'s, Impl>, Combinator)],
+                                      index: usize,
+                                      relative_selector_key: usize,
+                                      root: &N,
+                                      context: &mut MatchingContext,
+                                      shareable: &mut bool)
+                                      -> bool
+                                      where N: TNode<'a>,
+                                            N::Element: TElement<'a, Impl=Impl>,
+                                            Impl: SelectorImpl {
+    let root_key = (root.opaque(), relative_selector_key, index);
+    match context.relative_match_cache.get(&root_key) {
+        Some(&result) => return result,
+        None => {}
+    }
+
+    let mut node = match root.first_child() {
+        Some(child) => child,
+        None => return false,
+    };
+    loop {
+        if node.is_element() {
+            if starts_relative_match(compounds, index, relative_selector_key, &node, context,
+                                     shareable) {
+                cache_descendant_match(&node, root, relative_selector_key, index, context);
+                return true
+            }
+
+            let key = (node.opaque(), relative_selector_key, index);
+            let cached = context.relative_match_cache.get(&key).map(|&result| result);
+            let first_child = match cached {
+                Some(true) => {
+                    cache_descendant_match(&node, root, relative_selector_key, index, context);
+                    return true
+                }
+                Some(false) => None,
+                None => node.first_child(),
+            };
+            match first_child {
+                Some(child) => {
+                    node = child;
+                    continue
+                }
+                None => {}
+            }
+        }
+
+        // Move to the next node in tree order that is not a descendant of `node`,
+        // caching negative results for the subtrees we leave.
+        loop {
+            match node.next_sibling() {
+                Some(sibling) => {
+                    node = sibling;
+                    break
+                }
+                None => {
+                    node = node.parent_node().unwrap();
+                    context.relative_match_cache.insert(
+                        (node.opaque(), relative_selector_key, index), false);
+                    if node.opaque() == root_key.0 {
+                        return false
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Records that the ancestors of `node` up to `root` have a descendant that starts a match.
+fn cache_descendant_match<'a,N>(node: &N,
+                                root: &N,
+                                relative_selector_key: usize,
+                                index: usize,
+                                context: &mut MatchingContext)
+                                where N: TNode<'a> {
+    let root = root.opaque();
+    let mut node = node.clone();
+    while let Some(parent) = node.parent_node() {
+        context.relative_match_cache.insert((parent.opaque(), relative_selector_key, index), true);
+        if parent.opaque() == root {
+            break
+        }
+        node = parent;
+    }
+}
+
+/// `has_relative_match` for a subsequent-sibling combinator. The result is cached for `element`
+/// and for each of the siblings visited before the one that decided it.
+fn has_later_sibling_match<'a,'s,N,Impl>(compounds: &[(CompoundSelector<'s, Impl>, Combinator)],
+                                         index: usize,
+                                         relative_selector_key: usize,
+                                         element: &N,
+                                         context: &mut MatchingContext,
+                                         shareable: &mut bool)
+                                         -> bool
+                                         where N: TNode<'a>,
+                                               N::Element: TElement<'a, Impl=Impl>,
+                                               Impl: SelectorImpl {
+    match context.relative_match_cache.get(&(element.opaque(), relative_selector_key, index)) {
+        Some(&result) => return result,
+        None => {}
+    }
+
+    // The elements whose result is that of `element`.
+    let mut anchors = vec![element.opaque()];
+    let mut result = false;
+    let mut sibling = element.next_sibling();
+    while let Some(node) = sibling {
+        sibling = node.next_sibling();
+        if !node.is_element() {
+            continue
+        }
+        if starts_relative_match(compounds, index, relative_selector_key, &node, context,
+                                 shareable) {
+            result = true;
+            break
+        }
+        let key = (node.opaque(), relative_selector_key, index);
+        match context.relative_match_cache.get(&key) {
+            Some(&cached) => {
+                result = cached;
+                break
+            }
+            None => anchors.push(node.opaque()),
+        }
+    }
+    for anchor in anchors.into_iter() {
+        context.relative_match_cache.insert((anchor, relative_selector_key, index), result);
+    }
+    result
+}
+
+/// With `selectors`, for `:nth-child(An+B of S)`, only elements matching one of them match and
+/// are counted.
+#[inline]
+fn matches_generic_nth_child<'a,N,Impl>(element: &N,
+                                        a: i32,
+                                        b: i32,
+                                        is_of_type: bool,
+                                        is_from_end: bool,
+                                        selectors: Option<&[Selector<Impl>]>,
+                                        context: &mut MatchingContext,
+                                        shareable: &mut bool)
+                                        -> bool
+                                        where N: TNode<'a>,
+                                              N::Element: TElement<'a, Impl=Impl>,
+                                              Impl: SelectorImpl {
+    match selectors {
+        Some(selectors) if !matches_any_selector(selectors, element, context, shareable) => {
+            return false
+        }
+        _ => {}
+    }
+
+    let mut node = element.clone();
+    // fail if we can't find a parent or if the node is the root element
+    // of the document (Cf. Selectors Level 3)
+    match node.parent_node() {
+        Some(parent) => if parent.is_document() {
+            return false;
+        },
+        None => return false
+    };
+
+    let mut index = 1;
+    loop {
+        if is_from_end {
+            match node.next_sibling() {
+                None => break,
+                Some(next_sibling) => node = next_sibling
+            }
+        } else {
+            match node.prev_sibling() {
+                None => break,
+                Some(prev_sibling) => node = prev_sibling
             }
         }
 
@@ -834,7 +1855,14 @@ fn matches_generic_nth_child<'a,N>(element: &N,
                     index += 1;
                 }
             } else {
-              index += 1;
+                match selectors {
+                    Some(selectors) => {
+                        if matches_any_selector(selectors, &node, context, shareable) {
+                            index += 1;
+                        }
+                    }
+                    None => index += 1,
+                }
             }
         }
     }
@@ -855,6 +1883,20 @@ fn matches_root<'a,N>(element: &N) -> bool where N: TNode<'a> {
     }
 }
 
+/// Whether the element has no children other than comments, processing instructions and empty
+/// text nodes.
+#[inline]
+fn matches_empty<'a,N>(element: &N) -> bool where N: TNode<'a> {
+    let mut child = element.first_child();
+    while let Some(node) = child {
+        if node.is_element() || node.is_non_empty_text_node() {
+            return false
+        }
+        child = node.next_sibling();
+    }
+    true
+}
+
 #[inline]
 fn matches_first_child<'a,N>(element: &N) -> bool where N: TNode<'a> {
     let mut node = element.clone();
@@ -899,9 +1941,10 @@ fn matches_last_child<'a,N>(element: &N) -> bool where N: TNode<'a> {
     }
 }
 
-fn find_push<T>(map: &mut HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
-                key: Atom,
-                value: Rule<T>) {
+fn find_push<T, Impl>(map: &mut HashMap<Atom, Vec<Rule<T, Impl>>, DefaultState<FnvHasher>>,
+                      key: Atom,
+                      value: Rule<T, Impl>)
+                      where Impl: SelectorImpl {
     match map.get_mut(&key) {
         Some(vec) => {
             vec.push(value);
@@ -913,18 +1956,281 @@ fn find_push<T>(map: &mut HashMap<Atom, Vec<Rule<T>>, DefaultState<FnvHasher>>,
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use std::cmp::Ordering;
+    use std::fmt;
     use std::sync::Arc;
-    use super::{DeclarationBlock, Rule, SelectorMap};
-    use parser::LocalName;
-    use string_cache::Atom;
-    use cssparser::Parser;
+    use super::{DeclarationBlock, MatchingContext, QuirksMode, Rule, SelectorMap, matches};
+    use super::matches_shadow_pseudo_element;
+    use parser::{AttrSelector, LocalName, NamespaceConstraint, Selector, SelectorImpl};
+    use parser::parse_author_origin_selector_list_from_str;
+    use string_cache::{Atom, Namespace};
+    use cssparser::{Parser, ToCss};
     use parser::ParserContext;
+    use tree::{ElementState, TElement, TNode};
+
+    /// Only the standard pseudo-classes and pseudo-elements.
+    #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+    pub struct DummySelectorImpl;
+
+    /// Has no values, so that no selector can contain one.
+    #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+    pub enum NoPseudo {}
+
+    impl ToCss for NoPseudo {
+        fn to_css<W>(&self, _dest: &mut W) -> fmt::Result where W: fmt::Write {
+            match *self {}
+        }
+    }
+
+    impl SelectorImpl for DummySelectorImpl {
+        type NonTSPseudoClass = NoPseudo;
+        type PseudoElement = NoPseudo;
+    }
+
+    /// A tree of nodes in an HTML document, which is only a node of the tree if `append_document`
+    /// is called. Only elements use the fields other than `kind` and those of the tree structure.
+    pub struct MockTree {
+        nodes: Vec<MockNodeData>,
+    }
+
+    enum MockNodeKind {
+        Document,
+        /// The shadow root of the element at that index.
+        ShadowRoot(usize),
+        Element,
+        Text(String),
+        Comment,
+    }
+
+    struct MockNodeData {
+        kind: MockNodeKind,
+        local_name: Atom,
+        namespace: Namespace,
+        id: Option<Atom>,
+        classes: Vec<Atom>,
+        /// Attributes other than `id` and `class`.
+        attrs: Vec<(Namespace, Atom, String)>,
+        assigned_slot: Option<usize>,
+        parent: Option<usize>,
+        first_child: Option<usize>,
+        last_child: Option<usize>,
+        prev_sibling: Option<usize>,
+        next_sibling: Option<usize>,
+    }
+
+    impl MockTree {
+        pub fn new() -> MockTree {
+            MockTree {
+                nodes: vec![],
+            }
+        }
+
+        /// Appends an element to the children of `parent`, or as a new root, and returns its
+        /// index.
+        pub fn append(&mut self, parent: Option<usize>, local_name: &str, id: Option<&str>,
+                      classes: &[&str])
+                      -> usize {
+            self.append_node(parent, MockNodeKind::Element, local_name, id, classes)
+        }
+
+        /// A document, to append the root element to.
+        pub fn append_document(&mut self) -> usize {
+            self.append_node(None, MockNodeKind::Document, "", None, &[])
+        }
+
+        pub fn append_text(&mut self, parent: usize, data: &str) -> usize {
+            self.append_node(Some(parent), MockNodeKind::Text(data.to_owned()), "", None, &[])
+        }
+
+        pub fn append_comment(&mut self, parent: usize) -> usize {
+            self.append_node(Some(parent), MockNodeKind::Comment, "", None, &[])
+        }
+
+        /// A shadow root for `host`, to append the top-level nodes of its shadow tree to.
+        pub fn attach_shadow(&mut self, host: usize) -> usize {
+            self.append_node(None, MockNodeKind::ShadowRoot(host), "", None, &[])
+        }
+
+        /// Assigns the child of a shadow host at `index` to a slot of its shadow tree.
+        pub fn assign_slot(&mut self, index: usize, slot: usize) {
+            self.nodes[index].assigned_slot = Some(slot)
+        }
+
+        fn append_node(&mut self, parent: Option<usize>, kind: MockNodeKind, local_name: &str,
+                       id: Option<&str>, classes: &[&str])
+                       -> usize {
+            let index = self.nodes.len();
+            let prev_sibling = parent.and_then(|parent| self.nodes[parent].last_child);
+            self.nodes.push(MockNodeData {
+                kind: kind,
+                local_name: Atom::from_slice(local_name),
+                namespace: ns!(HTML),
+                id: id.map(Atom::from_slice),
+                classes: classes.iter().map(|class| Atom::from_slice(class)).collect(),
+                attrs: vec![],
+                assigned_slot: None,
+                parent: parent,
+                first_child: None,
+                last_child: None,
+                prev_sibling: prev_sibling,
+                next_sibling: None,
+            });
+            match prev_sibling {
+                Some(prev_sibling) => self.nodes[prev_sibling].next_sibling = Some(index),
+                None => match parent {
+                    Some(parent) => self.nodes[parent].first_child = Some(index),
+                    None => {}
+                },
+            }
+            match parent {
+                Some(parent) => self.nodes[parent].last_child = Some(index),
+                None => {}
+            }
+            index
+        }
+
+        /// Elements are in the HTML namespace unless this is called.
+        pub fn set_namespace(&mut self, index: usize, namespace: Namespace) {
+            self.nodes[index].namespace = namespace
+        }
+
+        /// Adds an attribute in the null namespace.
+        pub fn set_attr(&mut self, index: usize, name: &str, value: &str) {
+            self.set_attr_ns(index, ns!(""), name, value)
+        }
+
+        pub fn set_attr_ns(&mut self, index: usize, namespace: Namespace, name: &str,
+                           value: &str) {
+            self.nodes[index].attrs.push((namespace, Atom::from_slice(name), value.to_owned()))
+        }
+
+        pub fn node(&self, index: usize) -> MockNode {
+            MockNode {
+                tree: self,
+                index: index,
+            }
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct MockNode<'a> {
+        tree: &'a MockTree,
+        index: usize,
+    }
+
+    impl<'a> MockNode<'a> {
+        fn data(self) -> &'a MockNodeData {
+            let tree = self.tree;
+            &tree.nodes[self.index]
+        }
+
+        fn node(self, index: Option<usize>) -> Option<MockNode<'a>> {
+            index.map(|index| self.tree.node(index))
+        }
+    }
+
+    impl<'a> TNode<'a> for MockNode<'a> {
+        type Element = MockNode<'a>;
+
+        fn parent_node(self) -> Option<MockNode<'a>> { self.node(self.data().parent) }
+        fn first_child(self) -> Option<MockNode<'a>> { self.node(self.data().first_child) }
+        fn last_child(self) -> Option<MockNode<'a>> { self.node(self.data().last_child) }
+        fn prev_sibling(self) -> Option<MockNode<'a>> { self.node(self.data().prev_sibling) }
+        fn next_sibling(self) -> Option<MockNode<'a>> { self.node(self.data().next_sibling) }
+        fn is_document(self) -> bool {
+            match self.data().kind {
+                MockNodeKind::Document => true,
+                _ => false,
+            }
+        }
+        fn is_element(self) -> bool {
+            match self.data().kind {
+                MockNodeKind::Element => true,
+                _ => false,
+            }
+        }
+        fn is_non_empty_text_node(self) -> bool {
+            match self.data().kind {
+                MockNodeKind::Text(ref data) => !data.is_empty(),
+                _ => false,
+            }
+        }
+        fn as_element(self) -> MockNode<'a> { self }
+        fn shadow_root_host(self) -> Option<MockNode<'a>> {
+            match self.data().kind {
+                MockNodeKind::ShadowRoot(host) => Some(self.tree.node(host)),
+                _ => None,
+            }
+        }
+        fn assigned_slot(self) -> Option<MockNode<'a>> { self.node(self.data().assigned_slot) }
+        fn match_attr<F>(self, attr: &AttrSelector, test: F) -> bool
+                         where F: Fn(&str) -> bool {
+            let name = if self.is_html_element_in_html_document() {
+                &attr.lower_name
+            } else {
+                &attr.name
+            };
+            self.data().attrs.iter().any(|&(ref namespace, ref attr_name, ref value)| {
+                let namespace_matches = match attr.namespace {
+                    NamespaceConstraint::Any => true,
+                    NamespaceConstraint::Specific(ref attr_namespace) => {
+                        namespace == attr_namespace
+                    }
+                };
+                attr_name == name && namespace_matches && test(value)
+            })
+        }
+        fn is_html_element_in_html_document(self) -> bool {
+            self.data().namespace == ns!(HTML)
+        }
+        fn opaque(self) -> usize { self.index }
+
+        fn has_changed(self) -> bool { false }
+        unsafe fn set_changed(self, _value: bool) {}
+        fn is_dirty(self) -> bool { false }
+        unsafe fn set_dirty(self, _value: bool) {}
+        fn has_dirty_siblings(self) -> bool { false }
+        unsafe fn set_dirty_siblings(self, _value: bool) {}
+        fn has_dirty_descendants(self) -> bool { false }
+        unsafe fn set_dirty_descendants(self, _value: bool) {}
+    }
+
+    impl<'a> TElement<'a> for MockNode<'a> {
+        type Impl = DummySelectorImpl;
+
+        fn get_attr(self, namespace: &Namespace, attr: &Atom) -> Option<&'a str> {
+            self.data().attrs.iter().find(|&&(ref attr_namespace, ref name, _)| {
+                attr_namespace == namespace && name == attr
+            }).map(|&(_, _, ref value)| &**value)
+        }
+        fn get_attrs(self, attr: &Atom) -> Vec<&'a str> {
+            self.data().attrs.iter().filter(|&&(_, ref name, _)| name == attr).map(|entry| {
+                &*entry.2
+            }).collect()
+        }
+        fn get_link(self) -> Option<&'a str> { self.get_attr(&ns!(""), &atom!("href")) }
+        fn get_local_name(self) -> &'a Atom { &self.data().local_name }
+        fn get_namespace(self) -> &'a Namespace { &self.data().namespace }
+        fn get_id(self) -> Option<Atom> { self.data().id.clone() }
+        fn get_state(self) -> ElementState { ElementState::empty() }
+        fn has_class(self, name: &Atom) -> bool {
+            self.data().classes.iter().any(|class| class == name)
+        }
+        fn has_custom_state(self, _state: &Atom) -> bool { false }
+        fn match_non_ts_pseudo_class(self, pseudo_class: &NoPseudo) -> bool {
+            match *pseudo_class {}
+        }
+        fn each_class<F>(self, mut callback: F) where F: FnMut(&Atom) {
+            for class in self.data().classes.iter() {
+                callback(class)
+            }
+        }
+    }
 
     /// Helper method to get some Rules from selector strings.
     /// Each sublist of the result contains the Rules for one StyleRule.
-    fn get_mock_rules(css_selectors: &[&str]) -> Vec<Vec<Rule<()>>> {
+    pub fn get_mock_rules(css_selectors: &[&str]) -> Vec<Vec<Rule<(), DummySelectorImpl>>> {
         use parser::parse_selector_list;
 
         css_selectors.iter().enumerate().map(|(i, selectors)| {
@@ -932,17 +2238,372 @@ mod tests {
             parse_selector_list(&context, &mut Parser::new(*selectors))
             .unwrap().into_iter().map(|s| {
                 Rule {
-                    selector: s.compound_selectors.clone(),
                     declarations: DeclarationBlock {
                         specificity: s.specificity,
                         declarations: Arc::new(()),
                         source_order: i,
-                    }
+                    },
+                    selector: s,
                 }
             }).collect()
         }).collect()
     }
 
+    pub fn parse(css: &str) -> Vec<Selector<DummySelectorImpl>> {
+        parse_author_origin_selector_list_from_str(css).unwrap()
+    }
+
+    #[test]
+    fn test_has() {
+        let mut tree = MockTree::new();
+        let html = tree.append(None, "html", None, &[]);
+        let body = tree.append(Some(html), "body", None, &[]);
+        let d1 = tree.append(Some(body), "div", Some("d1"), &["a"]);
+        let p1 = tree.append(Some(d1), "p", None, &["b"]);
+        let d2 = tree.append(Some(body), "div", Some("d2"), &[]);
+        let span = tree.append(Some(d2), "span", None, &["a"]);
+        let em = tree.append(Some(span), "em", None, &[]);
+        let p2 = tree.append(Some(d2), "p", None, &["b"]);
+        let section = tree.append(Some(body), "section", None, &[]);
+        let h1 = tree.append(Some(section), "h1", None, &[]);
+        let h2 = tree.append(Some(section), "h2", None, &["x"]);
+        let i = tree.append(Some(h2), "i", None, &["b"]);
+        let elements = [html, body, d1, p1, d2, span, em, p2, section, h1, h2, i];
+
+        let check = |css: &str, expected: &[usize]| {
+            let selectors = parse(css);
+            // In tree order, then in reverse order with a new context, so that results come from
+            // the caches in different ways.
+            let mut context = MatchingContext::new();
+            for &element in elements.iter() {
+                assert!(matches(&selectors, &tree.node(element), &None, &mut context) ==
+                        expected.contains(&element), "{} on element {}", css, element);
+            }
+            let mut context = MatchingContext::new();
+            for &element in elements.iter().rev() {
+                assert!(matches(&selectors, &tree.node(element), &None, &mut context) ==
+                        expected.contains(&element), "{} on element {}", css, element);
+            }
+        };
+        check(":has(.b)", &[html, body, d1, d2, section, h2]);
+        check(":has(> .b)", &[d1, d2, h2]);
+        check(":has(.a .b)", &[html, body]);
+        check(":has(> .a .b)", &[body]);
+        check(":has(.a > .b)", &[html, body]);
+        check(":has(+ div p)", &[d1]);
+        check(":has(+ div > span > em)", &[d1]);
+        check(":has(~ section > h2 i)", &[d1, d2]);
+        check(":has(~ .x .b)", &[h1]);
+        check(":has(~ * > .b)", &[d1, h1]);
+        check(":has(~ p)", &[span]);
+        check("div:has(.a, + section)", &[d2]);
+        check(":not(:has(*))", &[p1, em, p2, h1, i]);
+    }
+
+    #[test]
+    fn test_has_deep_tree() {
+        let mut tree = MockTree::new();
+        let mut elements = vec![];
+        let mut parent = None;
+        for _ in 0..100000 {
+            let element = tree.append(parent, "div", None, &["a"]);
+            elements.push(element);
+            parent = Some(element);
+        }
+        let deepest = tree.append(parent, "div", None, &["b"]);
+        // Each ancestor of `deepest` is visited once per compound selector, not once per element
+        // `:has()` is matched against.
+        let selectors = parse(":has(.a .b)");
+        let mut context = MatchingContext::new();
+        for (depth, &element) in elements.iter().enumerate() {
+            assert_eq!(matches(&selectors, &tree.node(element), &None, &mut context),
+                       depth + 1 < elements.len());
+        }
+        assert!(!matches(&selectors, &tree.node(deepest), &None, &mut context));
+    }
+
+    #[test]
+    fn test_has_context_changes() {
+        let mut tree = MockTree::new();
+        let document = tree.append_document();
+        let html = tree.append(Some(document), "html", None, &[]);
+        let body = tree.append(Some(html), "body", None, &[]);
+        let div = tree.append(Some(body), "div", Some("x"), &["a"]);
+        let body = tree.node(body);
+        // The same context is reused, so results cached for the previous values must not be.
+        let mut context = MatchingContext::new();
+        let selectors = parse(":has(:scope)");
+        context.scope = Some(tree.node(div).opaque());
+        assert!(matches(&selectors, &body, &None, &mut context));
+        context.scope = None;
+        assert!(!matches(&selectors, &body, &None, &mut context));
+        let selectors = parse(":has(:target)");
+        context.document_url = Some("http://example.com/#x".to_owned());
+        assert!(matches(&selectors, &body, &None, &mut context));
+        context.document_url = Some("http://example.com/#y".to_owned());
+        assert!(!matches(&selectors, &body, &None, &mut context));
+        let selectors = parse(":has(.A)");
+        assert!(!matches(&selectors, &body, &None, &mut context));
+        context.quirks_mode = QuirksMode::Quirks;
+        assert!(matches(&selectors, &body, &None, &mut context));
+    }
+
+    #[test]
+    fn test_lang_matches() {
+        use super::lang_matches;
+        assert!(lang_matches("de", "de"));
+        assert!(lang_matches("de", "DE-ch"));
+        assert!(lang_matches("de-DE", "de-Latn-DE"));
+        assert!(lang_matches("de-*-DE", "de-Latn-DE-1996"));
+        assert!(lang_matches("*-CH", "fr-CH"));
+        assert!(lang_matches("*", "en"));
+        assert!(!lang_matches("de", "deu"));
+        assert!(!lang_matches("de-DE", "de"));
+        assert!(!lang_matches("de-DE", "de-x-DE"));
+        assert!(!lang_matches("en", ""));
+    }
+
+    #[test]
+    fn test_attribute_flags() {
+        let mut tree = MockTree::new();
+        let div = tree.append(None, "div", None, &[]);
+        // Not one of the attributes whose values are case-insensitive in HTML.
+        tree.set_attr(div, "title", "Foo-Bar baz");
+        tree.set_attr(div, "data-x", "\u{c9}t\u{c9}");
+        let matches_div = |css: &str| {
+            matches(&parse(css), &tree.node(div), &None, &mut MatchingContext::new())
+        };
+        assert!(!matches_div("[title='foo-bar baz']"));
+        assert!(matches_div("[title='foo-bar baz' i]"));
+        assert!(!matches_div("[title='foo-bar baz' s]"));
+        assert!(matches_div("[title='Foo-Bar baz' s]"));
+        assert!(!matches_div("[title~=BAZ]"));
+        assert!(matches_div("[title~=BAZ i]"));
+        assert!(!matches_div("[title~=BAZ s]"));
+        assert!(!matches_div("[title|=foo]"));
+        assert!(matches_div("[title|=foo i]"));
+        assert!(!matches_div("[title|=foo s]"));
+        assert!(matches_div("[title|=Foo s]"));
+        assert!(!matches_div("[title^=FOO]"));
+        assert!(matches_div("[title^=FOO i]"));
+        assert!(!matches_div("[title^=FOO s]"));
+        assert!(!matches_div("[title$=BAZ]"));
+        assert!(matches_div("[title$=BAZ i]"));
+        assert!(!matches_div("[title$=BAZ s]"));
+        assert!(!matches_div("[title*=-bar]"));
+        assert!(matches_div("[title*=-bar i]"));
+        assert!(!matches_div("[title*=-bar s]"));
+        // Only ASCII letters are folded.
+        assert!(matches_div("[data-x='\u{c9}t\u{c9}' i]"));
+        assert!(!matches_div("[data-x='\u{e9}t\u{e9}' i]"));
+        assert!(!matches_div("[data-x$='T\u{c9}' s]"));
+        assert!(matches_div("[data-x$='t\u{c9}' i]"));
+    }
+
+    #[test]
+    fn test_html_case_insensitive_attributes() {
+        let mut tree = MockTree::new();
+        let body = tree.append(None, "body", None, &[]);
+        let input = tree.append(Some(body), "input", None, &[]);
+        tree.set_attr(input, "type", "checkbox");
+        tree.set_attr(input, "title", "checkbox");
+        let svg = tree.append(Some(body), "svg", None, &[]);
+        tree.set_namespace(svg, ns!(SVG));
+        tree.set_attr(svg, "type", "checkbox");
+        let namespaced = tree.append(Some(body), "input", None, &[]);
+        tree.set_attr_ns(namespaced, ns!(XML), "type", "checkbox");
+        let matches_element = |css: &str, element: usize| {
+            matches(&parse(css), &tree.node(element), &None, &mut MatchingContext::new())
+        };
+        assert!(matches_element("[type=CHECKBOX]", input));
+        assert!(matches_element("[type^=Check]", input));
+        assert!(matches_element("[TYPE=CheckBox]", input));
+        assert!(!matches_element("[type=CHECKBOX s]", input));
+        assert!(matches_element("[type=checkbox s]", input));
+        assert!(!matches_element("[type=CHECKBOX]", svg));
+        assert!(matches_element("[type=CHECKBOX i]", svg));
+        assert!(!matches_element("[title=CHECKBOX]", input));
+        // With `*|`, case depends on the namespace of the attribute that matched.
+        assert!(matches_element("[*|type=CHECKBOX]", input));
+        assert!(matches_element("[*|type=checkbox]", input));
+        assert!(!matches_element("[*|type=CHECKBOX]", namespaced));
+        assert!(matches_element("[*|type=checkbox]", namespaced));
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut tree = MockTree::new();
+        let body = tree.append(None, "body", None, &[]);
+        let empty = tree.append(Some(body), "div", None, &[]);
+        let comment = tree.append(Some(body), "div", None, &[]);
+        tree.append_comment(comment);
+        let empty_text = tree.append(Some(body), "div", None, &[]);
+        tree.append_text(empty_text, "");
+        tree.append_comment(empty_text);
+        let whitespace = tree.append(Some(body), "div", None, &[]);
+        tree.append_text(whitespace, " ");
+        let child = tree.append(Some(body), "div", None, &[]);
+        tree.append(Some(child), "span", None, &[]);
+        let selectors = parse(":empty");
+        let matches_element = |element: usize| {
+            matches(&selectors, &tree.node(element), &None, &mut MatchingContext::new())
+        };
+        assert!(matches_element(empty));
+        assert!(matches_element(comment));
+        assert!(matches_element(empty_text));
+        assert!(!matches_element(whitespace));
+        assert!(!matches_element(child));
+        assert!(!matches_element(body));
+    }
+
+    #[test]
+    fn test_target() {
+        let mut tree = MockTree::new();
+        let body = tree.append(None, "body", None, &[]);
+        let cafe = tree.append(Some(body), "div", Some("caf\u{e9}"), &[]);
+        let encoded = tree.append(Some(body), "div", Some("caf%C3%A9"), &[]);
+        let percent = tree.append(Some(body), "div", Some("100%"), &[]);
+        let none = tree.append(Some(body), "div", None, &[]);
+        let selectors = parse(":target");
+        let matches_element = |document_url: Option<&str>, element: usize| {
+            let mut context = MatchingContext::new();
+            context.document_url = document_url.map(|url| url.to_owned());
+            matches(&selectors, &tree.node(element), &None, &mut context)
+        };
+        // Both the raw and the percent-decoded fragment are tried.
+        assert!(matches_element(Some("http://example.com/#caf%C3%A9"), cafe));
+        assert!(matches_element(Some("http://example.com/#caf%C3%A9"), encoded));
+        assert!(matches_element(Some("http://example.com/#caf\u{e9}"), cafe));
+        assert!(!matches_element(Some("http://example.com/#caf\u{e9}"), encoded));
+        assert!(!matches_element(Some("http://example.com/#caf%c3%a9x"), cafe));
+        // Invalid escapes are left as they are.
+        assert!(matches_element(Some("http://example.com/#100%"), percent));
+        assert!(!matches_element(Some("http://example.com/#"), none));
+        assert!(!matches_element(Some("http://example.com/"), cafe));
+        assert!(!matches_element(None, cafe));
+    }
+
+    #[test]
+    fn test_scope() {
+        let mut tree = MockTree::new();
+        let document = tree.append_document();
+        let html = tree.append(Some(document), "html", None, &[]);
+        let body = tree.append(Some(html), "body", None, &[]);
+        let div = tree.append(Some(body), "div", None, &[]);
+        let p = tree.append(Some(div), "p", None, &[]);
+        let matches_element = |css: &str, scope: Option<usize>, element: usize| {
+            let mut context = MatchingContext::new();
+            context.scope = scope.map(|scope| tree.node(scope).opaque());
+            matches(&parse(css), &tree.node(element), &None, &mut context)
+        };
+        // Without a scoping root, `:scope` is `:root`.
+        assert!(matches_element(":scope", None, html));
+        assert!(!matches_element(":scope", None, body));
+        assert!(matches_element(":scope", Some(div), div));
+        assert!(!matches_element(":scope", Some(div), html));
+        assert!(matches_element(":scope > p", Some(div), p));
+        assert!(!matches_element(":scope > p", Some(body), p));
+        assert!(matches_element(":scope p", Some(body), p));
+    }
+
+    #[test]
+    fn test_local_link() {
+        let mut tree = MockTree::new();
+        let body = tree.append(None, "body", None, &[]);
+        let same_page = tree.append(Some(body), "a", None, &[]);
+        tree.set_attr(same_page, "href", "http://example.com/page#section");
+        let other_page = tree.append(Some(body), "a", None, &[]);
+        tree.set_attr(other_page, "href", "http://example.com/other");
+        let no_link = tree.append(Some(body), "a", None, &[]);
+        let selectors = parse(":local-link");
+        let matches_element = |document_url: Option<&str>, element: usize| {
+            let mut context = MatchingContext::new();
+            context.document_url = document_url.map(|url| url.to_owned());
+            matches(&selectors, &tree.node(element), &None, &mut context)
+        };
+        // Fragments are ignored on both sides.
+        assert!(matches_element(Some("http://example.com/page"), same_page));
+        assert!(matches_element(Some("http://example.com/page#top"), same_page));
+        assert!(!matches_element(Some("http://example.com/page"), other_page));
+        assert!(!matches_element(Some("http://example.com/page"), no_link));
+        assert!(!matches_element(None, same_page));
+    }
+
+    #[test]
+    fn test_shadow_tree() {
+        let mut tree = MockTree::new();
+        let body = tree.append(None, "body", None, &["theme"]);
+        let host = tree.append(Some(body), "div", Some("host"), &["x"]);
+        let span = tree.append(Some(host), "span", None, &["s"]);
+        let shadow_root = tree.attach_shadow(host);
+        let p = tree.append(Some(shadow_root), "p", None, &[]);
+        let slot = tree.append(Some(shadow_root), "slot", None, &[]);
+        let inner = tree.append(Some(shadow_root), "div", None, &["inner"]);
+        tree.set_attr(inner, "part", "label title");
+        let em = tree.append(Some(inner), "em", None, &[]);
+        tree.assign_slot(span, slot);
+
+        // The rules of the shadow tree.
+        let matches_in_shadow_tree = |css: &str, element: usize| {
+            let mut context = MatchingContext::new();
+            context.shadow_host = Some(tree.node(host).opaque());
+            matches(&parse(css), &tree.node(element), &None, &mut context)
+        };
+        assert!(matches_in_shadow_tree(":host", host));
+        assert!(!matches(&parse(":host"), &tree.node(host), &None, &mut MatchingContext::new()));
+        assert!(!matches_in_shadow_tree(":host", inner));
+        assert!(matches_in_shadow_tree(":host(.x)", host));
+        assert!(!matches_in_shadow_tree(":host(.y)", host));
+        assert!(matches_in_shadow_tree(":host > p", p));
+        assert!(matches_in_shadow_tree(":host(#host) p", p));
+        assert!(matches_in_shadow_tree(":host em", em));
+        assert!(!matches_in_shadow_tree(":host > em", em));
+        assert!(matches_in_shadow_tree(":host-context(.theme) > p", p));
+        assert!(matches_in_shadow_tree(":host-context(.x) > p", p));
+        assert!(!matches_in_shadow_tree(":host-context(.other) > p", p));
+        // Only `:host` and `:host-context()` cross into the tree of the host: it is featureless
+        // to the other simple selectors.
+        assert!(!matches_in_shadow_tree("* > p", p));
+        assert!(!matches_in_shadow_tree("*|* > p", p));
+        assert!(!matches_in_shadow_tree("div > p", p));
+        assert!(!matches_in_shadow_tree(":host.x > p", p));
+        assert!(!matches_in_shadow_tree("body p", p));
+        assert!(!matches_in_shadow_tree(":host + p", p));
+        // Nor do they go on from the host into its tree.
+        assert!(!matches_in_shadow_tree("body :host > p", p));
+        assert!(!matches_in_shadow_tree(".theme :host", host));
+        assert!(!matches_in_shadow_tree("body > :host", host));
+        assert!(!matches_in_shadow_tree("body > *", host));
+        assert!(matches(&parse("body > .x"), &tree.node(host), &None, &mut MatchingContext::new()));
+        assert!(matches_in_shadow_tree(".inner > em", em));
+        assert!(matches_in_shadow_tree("p ~ div em", em));
+        let matches_pseudo_element = |css: &str, shadow_host: Option<usize>, element: usize| {
+            let mut context = MatchingContext::new();
+            context.shadow_host = shadow_host.map(|host| tree.node(host).opaque());
+            matches_shadow_pseudo_element(&parse(css)[0], &tree.node(element), &mut context)
+        };
+        assert!(matches_pseudo_element("::slotted(span)", Some(host), span));
+        assert!(matches_pseudo_element("slot::slotted(.s)", Some(host), span));
+        assert!(matches_pseudo_element(":host > slot::slotted(*)", Some(host), span));
+        assert!(!matches_pseudo_element("p::slotted(span)", Some(host), span));
+        assert!(!matches_pseudo_element("::slotted(.other)", Some(host), span));
+        assert!(!matches_pseudo_element("::slotted(*)", Some(host), em));
+
+        // The rules of the tree of the host.
+        assert!(matches_pseudo_element("#host::part(label)", None, inner));
+        assert!(matches_pseudo_element("body > .x::part(title label)", None, inner));
+        assert!(!matches_pseudo_element("#host::part(other)", None, inner));
+        assert!(!matches_pseudo_element("#host::part(label other)", None, inner));
+        assert!(!matches_pseudo_element("body::part(label)", None, inner));
+        assert!(!matches_pseudo_element("#host::part(label)", None, em));
+    }
+
+    #[test]
+    fn test_html_case_insensitive_attributes_are_sorted() {
+        use super::HTML_CASE_INSENSITIVE_ATTRIBUTES;
+        assert!(HTML_CASE_INSENSITIVE_ATTRIBUTES.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
     #[test]
     fn test_rule_ordering_same_specificity(){
         let rules_list = get_mock_rules(&["a.intro", "img.sidebar"]);
@@ -991,4 +2652,573 @@ mod tests {
         assert_eq!(0, selector_map.class_hash.get(&Atom::from_slice("intro")).unwrap()[0].declarations.source_order);
         assert!(selector_map.class_hash.get(&Atom::from_slice("foo")).is_none());
     }
+
+    #[test]
+    fn test_insert_quirks_mode_buckets(){
+        let rules_list = get_mock_rules(&[".Intro", "#TOP"]);
+        let mut selector_map = SelectorMap::new();
+        let mut quirks_selector_map = SelectorMap::with_quirks_mode(QuirksMode::Quirks);
+        for rules in rules_list.iter() {
+            selector_map.insert(rules[0].clone());
+            quirks_selector_map.insert(rules[0].clone());
+        }
+        assert!(selector_map.class_hash.get(&Atom::from_slice("Intro")).is_some());
+        assert!(selector_map.class_hash.get(&Atom::from_slice("intro")).is_none());
+        assert!(selector_map.id_hash.get(&Atom::from_slice("TOP")).is_some());
+        assert!(quirks_selector_map.class_hash.get(&Atom::from_slice("Intro")).is_none());
+        assert!(quirks_selector_map.class_hash.get(&Atom::from_slice("intro")).is_some());
+        assert_eq!(1, quirks_selector_map.id_hash.get(&atom!("top")).unwrap()[0].declarations.source_order);
+    }
+
+    #[test]
+    fn test_get_all_matching_rules() {
+        let mut tree = MockTree::new();
+        let html = tree.append(None, "html", None, &[]);
+        let body = tree.append(Some(html), "body", None, &[]);
+        let main = tree.append(Some(body), "div", Some("main"), &["content"]);
+        tree.append(Some(main), "h1", None, &[]);
+        let p = tree.append(Some(main), "p", None, &["intro"]);
+
+        let rules_list = get_mock_rules(&["p", ".intro", "div > p", "#main p", "body > p",
+                                          "h1 + p", "h1 ~ .intro", "p + h1", "html > p",
+                                          "span p", "html div#main.content > h1 ~ p"]);
+        let mut selector_map = SelectorMap::new();
+        for rules in rules_list.into_iter() {
+            for rule in rules.into_iter() {
+                selector_map.insert(rule)
+            }
+        }
+        let mut matching_rules = vec![];
+        selector_map.get_all_matching_rules(&tree.node(p), &None, &mut MatchingContext::new(),
+                                            &mut matching_rules, &mut false);
+        let mut source_orders = matching_rules.iter().map(|declarations| {
+            declarations.source_order
+        }).collect::<Vec<_>>();
+        source_orders.sort();
+        assert_eq!(source_orders, vec![0, 1, 2, 3, 5, 6, 10]);
+    }
+
+    #[test]
+    fn test_get_all_matching_rules_in_quirks_mode() {
+        use bloom::BloomFilter;
+        use super::insert_into_bloom_filter;
+
+        let mut tree = MockTree::new();
+        let main = tree.append(None, "div", Some("Main"), &["Content"]);
+        let p = tree.append(Some(main), "p", Some("top"), &["intro"]);
+        let rules_list = get_mock_rules(&["#Top", "#top", ".INTRO", "#main p", ".content > p",
+                                          "#other p", ".Content p"]);
+        let matching_source_orders = |quirks_mode: QuirksMode| {
+            let mut selector_map = SelectorMap::with_quirks_mode(quirks_mode);
+            for rules in rules_list.iter() {
+                for rule in rules.iter() {
+                    selector_map.insert(rule.clone())
+                }
+            }
+            let mut bf = box BloomFilter::new();
+            insert_into_bloom_filter(&mut bf, tree.node(main), quirks_mode);
+            let mut context = MatchingContext::new();
+            context.quirks_mode = quirks_mode;
+            let mut matching_rules = vec![];
+            selector_map.get_all_matching_rules(&tree.node(p), &Some(bf), &mut context,
+                                                &mut matching_rules, &mut false);
+            let mut source_orders = matching_rules.iter().map(|declarations| {
+                declarations.source_order
+            }).collect::<Vec<_>>();
+            source_orders.sort();
+            source_orders
+        };
+        assert_eq!(matching_source_orders(QuirksMode::NoQuirks), vec![1, 6]);
+        assert_eq!(matching_source_orders(QuirksMode::Quirks), vec![0, 1, 2, 3, 4, 6]);
+    }
+
+    #[test]
+    fn test_match_many_compound_selectors() {
+        use parser::{ParserContextBuilder, ParserLimits, parse_selector_list};
+
+        let context = ParserContextBuilder::new().limits(ParserLimits::unlimited()).build();
+        let mut tree = MockTree::new();
+        let mut deepest = None;
+        for _ in 0..100000 {
+            deepest = Some(tree.append(deepest, "div", None, &[]));
+        }
+        let deepest = tree.node(deepest.unwrap());
+        let matches_deepest = |leftmost: &str, combinator: &str, count: usize| {
+            let mut css = leftmost.to_owned();
+            for _ in 1..count {
+                css.push_str(combinator);
+                css.push_str("div");
+            }
+            let selectors: Vec<Selector<DummySelectorImpl>> =
+                parse_selector_list(&context, &mut Parser::new(&css)).unwrap();
+            matches(&selectors, &deepest, &None, &mut MatchingContext::new())
+        };
+        assert!(matches_deepest("div", " > ", 100000));
+        assert!(!matches_deepest("div", " > ", 100001));
+        assert!(matches_deepest("div", " ", 100000));
+        assert!(!matches_deepest("span", " ", 100000));
+        assert!(!matches_deepest("div", " ~ ", 100000));
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    //! Compares the contiguous layout of `ComplexSelector` with the linked list of boxed compound
+    //! selectors it replaced, which `LinkedCompoundSelector` reproduces along with the way it was
+    //! built and matched: each `*_linked_*` bench is the counterpart of the one without.
+    //! `test_heap_allocations` counts the heap allocations of each layout.
+
+    extern crate test;
+
+    use std::collections::HashMap;
+    use std::collections::hash_state::DefaultState;
+    use std::default::Default;
+    use std::sync::Arc;
+    use fnv::FnvHasher;
+    use quicksort::quicksort_by;
+    use string_cache::Atom;
+    use super::{DeclarationBlock, MatchingContext, Rule, SelectorMap, SelectorMatchingResult};
+    use super::matches_simple_selector;
+    use super::tests::{DummySelectorImpl, MockNode, MockTree};
+    use parser::{Combinator, ComplexSelector, Selector, SimpleSelector};
+    use parser::parse_author_origin_selector_list_from_str;
+    use tree::{TElement, TNode};
+
+    type SimpleSelectors = Vec<SimpleSelector<DummySelectorImpl>>;
+
+    /// The previous layout of complex selectors: a compound selector and a box for each of the
+    /// ones to its left.
+    #[derive(Clone)]
+    struct LinkedCompoundSelector {
+        simple_selectors: SimpleSelectors,
+        next: Option<(Box<LinkedCompoundSelector>, Combinator)>,
+    }
+
+    impl LinkedCompoundSelector {
+        /// Takes the same arguments as `ComplexSelector::new`, and links them from the leftmost
+        /// compound selector, like the parser did.
+        fn new(rightmost: SimpleSelectors, rest: Vec<(Combinator, SimpleSelectors)>)
+               -> LinkedCompoundSelector {
+            let mut next = None;
+            for (combinator, simple_selectors) in rest.into_iter().rev() {
+                let compound_selector = LinkedCompoundSelector {
+                    simple_selectors: simple_selectors,
+                    next: next,
+                };
+                next = Some((box compound_selector, combinator));
+            }
+            LinkedCompoundSelector {
+                simple_selectors: rightmost,
+                next: next,
+            }
+        }
+
+        /// Walks the vectors and boxes, not counting the heap memory of the simple selectors,
+        /// which is the same in both layouts.
+        fn heap_allocations(&self) -> usize {
+            let vector = if self.simple_selectors.capacity() == 0 { 0 } else { 1 };
+            match self.next {
+                Some((ref next, _)) => vector + 1 + next.heap_allocations(),
+                None => vector,
+            }
+        }
+
+        /// The number of compound selectors that are not empty, going from one to the next the
+        /// way matching does.
+        fn walk(&self) -> usize {
+            let mut count = 0;
+            let mut compound_selector = self;
+            loop {
+                if compound_selector.simple_selectors.first().is_some() {
+                    count += 1
+                }
+                compound_selector = match compound_selector.next {
+                    Some((ref next, _)) => &**next,
+                    None => return count,
+                }
+            }
+        }
+
+        /// `matches_compound_selector_internal` as it was for this layout, recursing once per
+        /// compound selector. The style sheet has nothing that needs the `MatchingContext`
+        /// features added since.
+        fn matches(&self, element: &MockNode, context: &mut MatchingContext)
+                   -> SelectorMatchingResult {
+            let mut shareable = false;
+            if !self.simple_selectors.iter().all(|simple_selector| {
+                matches_simple_selector(simple_selector, element, context, &mut shareable)
+            }) {
+                return SelectorMatchingResult::NotMatchedAndRestartFromClosestLaterSibling
+            }
+            let (next_selector, combinator) = match self.next {
+                None => return SelectorMatchingResult::Matched,
+                Some((ref next_selector, combinator)) => (next_selector, combinator),
+            };
+            let (siblings, candidate_not_found) = match combinator {
+                Combinator::Child | Combinator::Descendant => {
+                    (false, SelectorMatchingResult::NotMatchedGlobally)
+                }
+                Combinator::NextSibling | Combinator::LaterSibling => {
+                    (true, SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant)
+                }
+            };
+            let mut node = *element;
+            loop {
+                let next_node = if siblings { node.prev_sibling() } else { node.parent_node() };
+                match next_node {
+                    None => return candidate_not_found,
+                    Some(next_node) => node = next_node,
+                }
+                if !node.is_element() {
+                    continue
+                }
+                let result = next_selector.matches(&node, context);
+                match (result, combinator) {
+                    (SelectorMatchingResult::Matched, _) |
+                    (SelectorMatchingResult::NotMatchedGlobally, _) |
+                    (_, Combinator::NextSibling) |
+                    (SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant,
+                     Combinator::LaterSibling) => return result,
+                    (_, Combinator::Child) => {
+                        return SelectorMatchingResult::NotMatchedAndRestartFromClosestDescendant
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    type LinkedRules = Vec<(LinkedCompoundSelector, DeclarationBlock<()>)>;
+    type LinkedRuleHash = HashMap<Atom, LinkedRules, DefaultState<FnvHasher>>;
+
+    /// `SelectorMap` for `LinkedCompoundSelector`, for HTML elements in documents that are not in
+    /// quirks mode.
+    struct LinkedSelectorMap {
+        id_hash: LinkedRuleHash,
+        class_hash: LinkedRuleHash,
+        lower_local_name_hash: LinkedRuleHash,
+        universal_rules: LinkedRules,
+    }
+
+    impl LinkedSelectorMap {
+        fn new() -> LinkedSelectorMap {
+            LinkedSelectorMap {
+                id_hash: HashMap::with_hash_state(Default::default()),
+                class_hash: HashMap::with_hash_state(Default::default()),
+                lower_local_name_hash: HashMap::with_hash_state(Default::default()),
+                universal_rules: vec![],
+            }
+        }
+
+        fn insert(&mut self, selector: LinkedCompoundSelector, declarations: DeclarationBlock<()>) {
+            let mut id = None;
+            let mut class = None;
+            let mut local_name = None;
+            for simple_selector in selector.simple_selectors.iter() {
+                match *simple_selector {
+                    SimpleSelector::ID(ref name) if id.is_none() => id = Some(name.clone()),
+                    SimpleSelector::Class(ref name) if class.is_none() => {
+                        class = Some(name.clone())
+                    }
+                    SimpleSelector::LocalName(ref name) if local_name.is_none() => {
+                        local_name = Some(name.lower_name.clone())
+                    }
+                    _ => {}
+                }
+            }
+            let rule = (selector, declarations);
+            match (id, class, local_name) {
+                (Some(id), _, _) => push_rule(&mut self.id_hash, id, rule),
+                (None, Some(class), _) => push_rule(&mut self.class_hash, class, rule),
+                (None, None, Some(name)) => push_rule(&mut self.lower_local_name_hash, name, rule),
+                (None, None, None) => self.universal_rules.push(rule),
+            }
+
+            fn push_rule(hash: &mut LinkedRuleHash, key: Atom,
+                         rule: (LinkedCompoundSelector, DeclarationBlock<()>)) {
+                match hash.get_mut(&key) {
+                    Some(rules) => {
+                        rules.push(rule);
+                        return
+                    }
+                    None => {}
+                }
+                hash.insert(key, vec![rule]);
+            }
+        }
+
+        fn get_all_matching_rules(&self,
+                                  element: &MockNode,
+                                  context: &mut MatchingContext,
+                                  matching_rules: &mut Vec<DeclarationBlock<()>>) {
+            let init_len = matching_rules.len();
+            match element.get_id() {
+                Some(id) => get_matching_rules(self.id_hash.get(&id), element, context,
+                                               matching_rules),
+                None => {}
+            }
+            element.each_class(|class| {
+                get_matching_rules(self.class_hash.get(class), element, context, matching_rules)
+            });
+            get_matching_rules(self.lower_local_name_hash.get(element.get_local_name()), element,
+                               context, matching_rules);
+            get_matching_rules(Some(&self.universal_rules), element, context, matching_rules);
+            quicksort_by(&mut matching_rules[init_len..], |a, b| {
+                (a.specificity, a.source_order).cmp(&(b.specificity, b.source_order))
+            });
+
+            fn get_matching_rules(rules: Option<&LinkedRules>,
+                                  element: &MockNode,
+                                  context: &mut MatchingContext,
+                                  matching_rules: &mut Vec<DeclarationBlock<()>>) {
+                let rules = match rules {
+                    Some(rules) => rules,
+                    None => return,
+                };
+                for &(ref selector, ref declarations) in rules.iter() {
+                    match selector.matches(element, context) {
+                        SelectorMatchingResult::Matched => {
+                            matching_rules.push(declarations.clone())
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like `LinkedCompoundSelector::walk`.
+    fn walk(selector: &ComplexSelector<DummySelectorImpl>) -> usize {
+        selector.rightmost_compound().compounds_right_to_left().filter(|&(compound_selector, _)| {
+            compound_selector.simple_selectors().next().is_some()
+        }).count()
+    }
+
+    /// 300 selectors of three or four compound selectors each.
+    fn parse_stylesheet() -> Vec<Selector<DummySelectorImpl>> {
+        let mut css = String::new();
+        for i in 0..100 {
+            if i > 0 {
+                css.push_str(", ");
+            }
+            css.push_str(&format!("#main .item-{0} > a.link, ul li.item-{0} + li, \
+                                   div.c{0} span ~ em.x p", i));
+        }
+        parse_author_origin_selector_list_from_str(&css).unwrap()
+    }
+
+    /// The compound selectors of the style sheet, as the parser collects them before putting
+    /// them together: the arguments of `ComplexSelector::new`.
+    fn parse_compound_selectors() -> Vec<(SimpleSelectors, Vec<(Combinator, SimpleSelectors)>)> {
+        parse_stylesheet().iter().map(|selector| {
+            let mut rightmost = vec![];
+            let mut rest = vec![];
+            let mut combinator = None;
+            for (compound_selector, next_combinator) in selector.compounds_right_to_left() {
+                let simple_selectors = compound_selector.simple_selectors().map(|simple_selector| {
+                    simple_selector.clone()
+                }).collect();
+                match combinator {
+                    None => rightmost = simple_selectors,
+                    Some(combinator) => rest.push((combinator, simple_selectors)),
+                }
+                combinator = next_combinator;
+            }
+            (rightmost, rest)
+        }).collect()
+    }
+
+    fn linked_stylesheet() -> Vec<LinkedCompoundSelector> {
+        parse_compound_selectors().into_iter().map(|(rightmost, rest)| {
+            LinkedCompoundSelector::new(rightmost, rest)
+        }).collect()
+    }
+
+    /// Links in lists that the style sheet matches, and returns them.
+    fn build_tree(tree: &mut MockTree) -> Vec<usize> {
+        let html = tree.append(None, "html", None, &[]);
+        let body = tree.append(Some(html), "body", None, &[]);
+        let main = tree.append(Some(body), "div", Some("main"), &[]);
+        let ul = tree.append(Some(main), "ul", None, &[]);
+        let mut links = vec![];
+        for i in 0..100 {
+            let class = format!("item-{}", i);
+            let li = tree.append(Some(ul), "li", None, &[&class[..]]);
+            links.push(tree.append(Some(li), "a", None, &["link"]));
+        }
+        links
+    }
+
+    #[bench]
+    fn parse_selectors(b: &mut test::Bencher) {
+        b.iter(|| {
+            test::black_box(parse_stylesheet());
+        })
+    }
+
+    /// The part of `parse_selectors` that depends on the layout.
+    #[bench]
+    fn build_complex_selectors(b: &mut test::Bencher) {
+        let compound_selectors = parse_compound_selectors();
+        b.iter(|| {
+            test::black_box(compound_selectors.iter().map(|&(ref rightmost, ref rest)| {
+                ComplexSelector::new(rightmost.clone(), rest.clone())
+            }).collect::<Vec<_>>());
+        })
+    }
+
+    #[bench]
+    fn build_linked_compound_selectors(b: &mut test::Bencher) {
+        let compound_selectors = parse_compound_selectors();
+        b.iter(|| {
+            test::black_box(compound_selectors.iter().map(|&(ref rightmost, ref rest)| {
+                LinkedCompoundSelector::new(rightmost.clone(), rest.clone())
+            }).collect::<Vec<_>>());
+        })
+    }
+
+    /// Cloning a complex selector makes the same allocations as storing it did.
+    #[bench]
+    fn clone_complex_selectors(b: &mut test::Bencher) {
+        let selectors = parse_stylesheet();
+        b.iter(|| {
+            test::black_box(selectors.iter().map(|selector| {
+                (*selector.complex_selector).clone()
+            }).collect::<Vec<_>>());
+        })
+    }
+
+    #[bench]
+    fn clone_linked_compound_selectors(b: &mut test::Bencher) {
+        let selectors = linked_stylesheet();
+        b.iter(|| {
+            test::black_box(selectors.clone());
+        })
+    }
+
+    #[bench]
+    fn walk_complex_selectors(b: &mut test::Bencher) {
+        let selectors = parse_stylesheet();
+        b.iter(|| {
+            test::black_box(selectors.iter().fold(0, |count, selector| {
+                count + walk(&selector.complex_selector)
+            }));
+        })
+    }
+
+    #[bench]
+    fn walk_linked_compound_selectors(b: &mut test::Bencher) {
+        let selectors = linked_stylesheet();
+        b.iter(|| {
+            test::black_box(selectors.iter().fold(0, |count, selector| count + selector.walk()));
+        })
+    }
+
+    #[bench]
+    fn get_all_matching_rules(b: &mut test::Bencher) {
+        let mut selector_map = SelectorMap::new();
+        for (i, selector) in parse_stylesheet().into_iter().enumerate() {
+            selector_map.insert(Rule {
+                declarations: DeclarationBlock {
+                    specificity: selector.specificity,
+                    declarations: Arc::new(()),
+                    source_order: i,
+                },
+                selector: selector,
+            })
+        }
+        let mut tree = MockTree::new();
+        let links = build_tree(&mut tree);
+
+        b.iter(|| {
+            let mut context = MatchingContext::new();
+            let mut matching_rules = vec![];
+            for &link in links.iter() {
+                selector_map.get_all_matching_rules(&tree.node(link), &None, &mut context,
+                                                    &mut matching_rules, &mut false);
+            }
+            test::black_box(matching_rules);
+        })
+    }
+
+    #[bench]
+    fn get_all_linked_matching_rules(b: &mut test::Bencher) {
+        let mut selector_map = LinkedSelectorMap::new();
+        let specificities = parse_stylesheet().iter().map(|selector| {
+            selector.specificity
+        }).collect::<Vec<_>>();
+        for (i, selector) in linked_stylesheet().into_iter().enumerate() {
+            selector_map.insert(selector, DeclarationBlock {
+                specificity: specificities[i],
+                declarations: Arc::new(()),
+                source_order: i,
+            })
+        }
+        let mut tree = MockTree::new();
+        let links = build_tree(&mut tree);
+
+        b.iter(|| {
+            let mut context = MatchingContext::new();
+            let mut matching_rules = vec![];
+            for &link in links.iter() {
+                selector_map.get_all_matching_rules(&tree.node(link), &mut context,
+                                                    &mut matching_rules);
+            }
+            test::black_box(matching_rules);
+        })
+    }
+
+    /// Both layouts find the same rules, so that the `get_all_*matching_rules` benches compare
+    /// the same work.
+    #[test]
+    fn test_get_all_linked_matching_rules() {
+        let mut selector_map = SelectorMap::new();
+        let mut linked_selector_map = LinkedSelectorMap::new();
+        let selectors = parse_stylesheet().into_iter().zip(linked_stylesheet().into_iter());
+        for (i, (selector, linked)) in selectors.enumerate() {
+            let declarations = DeclarationBlock {
+                specificity: selector.specificity,
+                declarations: Arc::new(()),
+                source_order: i,
+            };
+            linked_selector_map.insert(linked, declarations.clone());
+            selector_map.insert(Rule {
+                declarations: declarations,
+                selector: selector,
+            })
+        }
+        let mut tree = MockTree::new();
+        let links = build_tree(&mut tree);
+        let mut context = MatchingContext::new();
+        for &link in links.iter() {
+            let mut matching_rules = vec![];
+            selector_map.get_all_matching_rules(&tree.node(link), &None, &mut context,
+                                                &mut matching_rules, &mut false);
+            let mut linked_matching_rules = vec![];
+            linked_selector_map.get_all_matching_rules(&tree.node(link), &mut context,
+                                                       &mut linked_matching_rules);
+            let source_orders = |rules: &[DeclarationBlock<()>]| {
+                rules.iter().map(|rule| rule.source_order).collect::<Vec<_>>()
+            };
+            assert_eq!(matching_rules.len(), 1);
+            assert_eq!(source_orders(&matching_rules), source_orders(&linked_matching_rules));
+        }
+    }
+
+    /// Walks the buffers of each layout. A counting allocator would need `#[global_allocator]`,
+    /// which this toolchain doesn't have. Neither count includes the `Arc` around the complex
+    /// selector, which both layouts have.
+    #[test]
+    fn test_heap_allocations() {
+        let contiguous = parse_stylesheet().iter().fold(0, |allocations, selector| {
+            // The one vector of components.
+            allocations + if selector.complex_selector.components().is_empty() { 0 } else { 1 }
+        });
+        let linked = linked_stylesheet().iter().fold(0, |allocations, selector| {
+            allocations + selector.heap_allocations()
+        });
+        // A vector for each of the 1000 compound selectors, and a box for each of the 700 that
+        // are not the rightmost.
+        assert_eq!((contiguous, linked), (300, 1700));
+    }
 }
@@ -4,56 +4,549 @@
 
 use std::ascii::{AsciiExt, OwnedAsciiExt};
 use std::borrow::Cow;
-use std::cmp;
 use std::collections::HashMap;
 use std::collections::hash_state::DefaultState;
 use std::default::Default;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+use std::slice;
 use std::sync::Arc;
+use std::usize;
 
-use cssparser::{Token, Parser, parse_nth};
+use cssparser::{ToCss, Token, Parser, Delimiter, SourceLocation, SourcePosition, parse_nth};
+use cssparser::{serialize_identifier, serialize_string};
 use string_cache::{Atom, Namespace};
 
 use fnv::FnvHasher;
 
+bitflags! {
+    #[doc = "Selector features that can be turned off in a `ParserContext`. Selectors using a"]
+    #[doc = "disabled feature fail to parse as if it was unknown."]
+    flags ParserFeatures: u8 {
+        #[doc = "`:has()`"]
+        const HAS_PSEUDO_CLASS = 0x01,
+        #[doc = "`:host`, `:host()`, `:host-context()`, `::slotted()` and `::part()`"]
+        const SHADOW_DOM_SELECTORS = 0x02,
+        #[doc = "`:state()`"]
+        const CUSTOM_STATE_PSEUDO_CLASS = 0x04,
+    }
+}
+
+/// What the selectors of a style sheet are parsed with, as built by `ParserContextBuilder`.
+#[derive(Clone)]
 pub struct ParserContext {
-    pub in_user_agent_stylesheet: bool,
-    pub default_namespace: Option<Namespace>,
-    pub namespace_prefixes: HashMap<String, Namespace, DefaultState<FnvHasher>>,
+    in_user_agent_stylesheet: bool,
+    default_namespace: Option<Namespace>,
+    namespace_prefixes: HashMap<String, Namespace, DefaultState<FnvHasher>>,
+    features: ParserFeatures,
+    limits: ParserLimits,
 }
 
 impl ParserContext {
+    /// A context for an author style sheet without `@namespace` rules, with all features.
     pub fn new() -> ParserContext {
-        ParserContext {
-            in_user_agent_stylesheet: false,
-            default_namespace: None,
-            namespace_prefixes: HashMap::with_hash_state(Default::default()),
+        ParserContextBuilder::new().build()
+    }
+
+    pub fn in_user_agent_stylesheet(&self) -> bool {
+        self.in_user_agent_stylesheet
+    }
+
+    pub fn default_namespace(&self) -> Option<&Namespace> {
+        self.default_namespace.as_ref()
+    }
+
+    pub fn namespace_for_prefix(&self, prefix: &str) -> Option<&Namespace> {
+        self.namespace_prefixes.get(prefix)
+    }
+
+    pub fn features(&self) -> ParserFeatures {
+        self.features
+    }
+
+    pub fn limits(&self) -> &ParserLimits {
+        &self.limits
+    }
+}
+
+/// Bounds on the size of the selectors a `ParserContext` parses, for style sheets that can't be
+/// trusted. Parsing fails with `SelectorParseErrorKind::LimitExceeded` beyond them.
+///
+/// The defaults are far above what style sheets written by hand need.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct ParserLimits {
+    /// Selectors in a list, including that of a pseudo-class like `:is()`. 1024 by default.
+    pub max_selectors_per_list: usize,
+    /// Compound selectors in a complex selector. 128 by default.
+    pub max_compound_selectors: usize,
+    /// Simple selectors in a compound selector, counting a pseudo-element and the pseudo-classes
+    /// after it. A type selector with a namespace counts as two. 128 by default.
+    pub max_simple_selectors_per_compound: usize,
+    /// How many functional pseudo-classes and pseudo-elements, like `:not()`, can be nested in
+    /// each other. 16 by default.
+    pub max_nesting_depth: usize,
+    /// The length in bytes of a selector list. 64 KiB by default.
+    pub max_input_length: usize,
+}
+
+impl ParserLimits {
+    /// No limits, for trusted style sheets.
+    pub fn unlimited() -> ParserLimits {
+        ParserLimits {
+            max_selectors_per_list: usize::MAX,
+            max_compound_selectors: usize::MAX,
+            max_simple_selectors_per_compound: usize::MAX,
+            max_nesting_depth: usize::MAX,
+            max_input_length: usize::MAX,
+        }
+    }
+}
+
+impl Default for ParserLimits {
+    fn default() -> ParserLimits {
+        ParserLimits {
+            max_selectors_per_list: 1024,
+            max_compound_selectors: 128,
+            max_simple_selectors_per_compound: 128,
+            max_nesting_depth: 16,
+            max_input_length: 64 * 1024,
         }
     }
 }
 
+/// Which of the `ParserLimits` a selector went past.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ParserLimit {
+    SelectorsPerList,
+    CompoundSelectors,
+    SimpleSelectorsPerCompound,
+    NestingDepth,
+    InputLength,
+}
 
+/// Why a namespace could not be registered with a `ParserContextBuilder`.
 #[derive(PartialEq, Clone, Debug)]
-pub struct Selector {
-    pub compound_selectors: Arc<CompoundSelector>,
-    pub pseudo_element: Option<PseudoElement>,
-    pub specificity: u32,
+pub enum NamespaceError {
+    /// The prefix was already declared.
+    DuplicatePrefix(String),
+    /// The default namespace was already declared.
+    DuplicateDefaultNamespace,
+    /// The prefix is not an identifier.
+    InvalidPrefix(String),
+    /// The body of an `@namespace` rule is not an optional prefix followed by a URL or string.
+    InvalidRule,
 }
 
-#[derive(Eq, PartialEq, Clone, Hash, Copy, Debug)]
-pub enum PseudoElement {
+/// Builds a `ParserContext`, typically from the `@namespace` rules of a style sheet:
+///
+/// ```ignore
+/// let mut builder = ParserContextBuilder::new();
+/// try!(builder.parse_namespace_rule(&mut Parser::new("svg url(http://www.w3.org/2000/svg)")));
+/// let context = builder.build();
+/// ```
+pub struct ParserContextBuilder {
+    context: ParserContext,
+}
+
+impl ParserContextBuilder {
+    pub fn new() -> ParserContextBuilder {
+        ParserContextBuilder {
+            context: ParserContext {
+                in_user_agent_stylesheet: false,
+                default_namespace: None,
+                namespace_prefixes: HashMap::with_hash_state(Default::default()),
+                features: ParserFeatures::all(),
+                limits: Default::default(),
+            },
+        }
+    }
+
+    /// Whether the selectors are those of a user agent style sheet, which can use
+    /// `SelectorImpl` pseudo-classes and pseudo-elements that authors can't. `false` by default.
+    pub fn in_user_agent_stylesheet(&mut self, value: bool) -> &mut ParserContextBuilder {
+        self.context.in_user_agent_stylesheet = value;
+        self
+    }
+
+    /// All features are enabled by default.
+    pub fn enable_features(&mut self, features: ParserFeatures) -> &mut ParserContextBuilder {
+        self.context.features.insert(features);
+        self
+    }
+
+    pub fn disable_features(&mut self, features: ParserFeatures) -> &mut ParserContextBuilder {
+        self.context.features.remove(features);
+        self
+    }
+
+    /// `ParserLimits::default()` by default.
+    pub fn limits(&mut self, limits: ParserLimits) -> &mut ParserContextBuilder {
+        self.context.limits = limits;
+        self
+    }
+
+    /// Declares `namespace` as the default namespace if `prefix` is `None`, and as the namespace
+    /// of `prefix` otherwise. Prefixes are case-sensitive.
+    pub fn add_namespace(&mut self, prefix: Option<&str>, namespace: Namespace)
+                         -> Result<(), NamespaceError> {
+        match prefix {
+            None => {
+                if self.context.default_namespace.is_some() {
+                    return Err(NamespaceError::DuplicateDefaultNamespace)
+                }
+                self.context.default_namespace = Some(namespace);
+            }
+            Some(prefix) => {
+                // Any other string can be written as an identifier with escapes.
+                if prefix.is_empty() {
+                    return Err(NamespaceError::InvalidPrefix(prefix.to_owned()))
+                }
+                if self.context.namespace_prefixes.contains_key(prefix) {
+                    return Err(NamespaceError::DuplicatePrefix(prefix.to_owned()))
+                }
+                self.context.namespace_prefixes.insert(prefix.to_owned(), namespace);
+            }
+        }
+        Ok(())
+    }
+
+    /// Parses the prelude of an `@namespace` rule, e.g. `svg url(http://www.w3.org/2000/svg)`,
+    /// and declares its namespace.
+    ///
+    /// https://drafts.csswg.org/css-namespaces/#syntax
+    pub fn parse_namespace_rule(&mut self, input: &mut Parser) -> Result<(), NamespaceError> {
+        let prefix = input.try(|input| input.expect_ident()).ok().map(|prefix| prefix.into_owned());
+        let url = try!(input.expect_url_or_string().map_err(|()| NamespaceError::InvalidRule));
+        try!(input.expect_exhausted().map_err(|()| NamespaceError::InvalidRule));
+        self.add_namespace(prefix.as_ref().map(|prefix| &**prefix),
+                           Namespace(Atom::from_slice(&url)))
+    }
+
+    pub fn build(&self) -> ParserContext {
+        self.context.clone()
+    }
+}
+
+
+/// Why a selector failed to parse, and where.
+#[derive(PartialEq, Clone, Debug)]
+pub struct SelectorParseError {
+    pub kind: SelectorParseErrorKind,
+    /// The start of the token at which parsing failed.
+    pub location: SourceLocation,
+}
+
+impl SelectorParseError {
+    fn new(kind: SelectorParseErrorKind, location: SourceLocation) -> SelectorParseError {
+        SelectorParseError {
+            kind: kind,
+            location: location,
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub enum SelectorParseErrorKind {
+    /// Nothing where a compound selector was expected, e.g. an empty input or `a, , b`.
+    EmptySelector,
+    /// A combinator with no compound selector after it, e.g. `a >`.
+    DanglingCombinator,
+    /// `.` not immediately followed by an identifier.
+    ExpectedClassName,
+    /// `prefix|` not followed by an identifier (or `*` outside of an attribute selector).
+    ExpectedLocalName,
+    /// A namespace prefix that was not declared with `@namespace`.
+    UndeclaredNamespacePrefix(String),
+    /// An attribute selector that does not start with a (possibly qualified) name.
+    ExpectedAttributeName,
+    /// Something other than `=`, `~=`, `|=`, `^=`, `$=` or `*=` after an attribute name.
+    BadAttributeOperator,
+    /// An attribute operator not followed by an identifier or a string.
+    ExpectedAttributeValue,
+    /// Something other than `i` or `s` after an attribute value.
+    BadAttributeFlags,
+    UnknownPseudoClass(String),
+    UnknownPseudoElement(String),
+    /// A combinator, or a simple selector other than a user-action pseudo-class, after a
+    /// pseudo-element.
+    PseudoElementNotLast,
+    /// The argument of `:nth-*()` is not a valid `an+b`.
+    InvalidNth,
+    /// A pseudo-element in the argument of a pseudo-class like `:not()`.
+    PseudoElementInArgument,
+    /// `:has()` in the argument of `:has()`, even through `:not()` or `:nth-child(An+B of S)`.
+    NestedHas,
+    /// Any other token that does not belong where it was found.
+    UnexpectedToken,
+    /// The selector is larger than the `ParserLimits` of the context allow.
+    LimitExceeded(ParserLimit),
+}
+
+
+/// The pseudo-classes and pseudo-elements that a user of this crate supports on top of the
+/// standard ones built into `SimpleSelector` and `PseudoElement`.
+///
+/// Parsing tries these hooks only for names that are not standard.
+pub trait SelectorImpl: Sized + Eq + Clone + Hash + fmt::Debug {
+    /// Pseudo-classes that are not tree-structural, like Servo's `:-servo-nonzero-border`.
+    /// Elements match them through `TElement::match_non_ts_pseudo_class`.
+    ///
+    /// Serializes with its leading colon.
+    type NonTSPseudoClass: Eq + Clone + Hash + fmt::Debug + ToCss;
+
+    /// Serializes with its leading `::`.
+    type PseudoElement: Eq + Clone + Hash + fmt::Debug + ToCss;
+
+    /// `name` is that of a pseudo-class, without the colon.
+    fn parse_non_ts_pseudo_class(_context: &ParserContext, _name: &str)
+                                 -> Result<Self::NonTSPseudoClass, ()> {
+        Err(())
+    }
+
+    /// `name` is that of a functional pseudo-class, and `arguments` the content of its
+    /// parentheses. Anything left in `arguments` makes the selector invalid.
+    fn parse_non_ts_functional_pseudo_class(_context: &ParserContext, _name: &str,
+                                            _arguments: &mut Parser)
+                                            -> Result<Self::NonTSPseudoClass, ()> {
+        Err(())
+    }
+
+    /// `name` is that of a pseudo-element, without the colons.
+    fn parse_pseudo_element(_context: &ParserContext, _name: &str)
+                            -> Result<Self::PseudoElement, ()> {
+        Err(())
+    }
+
+    /// Like `parse_non_ts_functional_pseudo_class`, for pseudo-elements.
+    fn parse_functional_pseudo_element(_context: &ParserContext, _name: &str,
+                                       _arguments: &mut Parser)
+                                       -> Result<Self::PseudoElement, ()> {
+        Err(())
+    }
+}
+
+
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub struct Selector<Impl: SelectorImpl> {
+    pub complex_selector: Arc<ComplexSelector<Impl>>,
+    pub pseudo_element: Option<PseudoElement<Impl>>,
+    /// User-action pseudo-classes after the pseudo-element, like `:hover` in `a::before:hover`.
+    /// They apply to the originating element.
+    pub pseudo_element_state: Vec<SimpleSelector<Impl>>,
+    pub specificity: Specificity,
+}
+
+/// https://drafts.csswg.org/selectors-4/#specificity-rules
+///
+/// Compares like the (a, b, c) tuple. The counts don't saturate.
+#[derive(Eq, PartialEq, PartialOrd, Ord, Clone, Copy, Hash, Debug, Default)]
+pub struct Specificity {
+    // Field order matters for the derived `Ord`.
+    a: u32,
+    b: u32,
+    c: u32,
+}
+
+impl Specificity {
+    pub fn new(a: u32, b: u32, c: u32) -> Specificity {
+        Specificity { a: a, b: b, c: c }
+    }
+
+    /// The number of ID selectors.
+    pub fn a(&self) -> u32 {
+        self.a
+    }
+
+    /// The number of class selectors, attribute selectors and pseudo-classes.
+    pub fn b(&self) -> u32 {
+        self.b
+    }
+
+    /// The number of type selectors and pseudo-elements.
+    pub fn c(&self) -> u32 {
+        self.c
+    }
+}
+
+impl fmt::Display for Specificity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({},{},{})", self.a, self.b, self.c)
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub enum PseudoElement<Impl: SelectorImpl> {
     Before,
     After,
-    // ...
+    FirstLine,
+    FirstLetter,
+    Selection,
+    Placeholder,
+    Marker,
+    Backdrop,
+    FileSelectorButton,
+    /// `::part(foo bar)`
+    Part(Vec<Atom>),
+    /// `::slotted()`, whose argument is a single compound selector.
+    Slotted(Vec<SimpleSelector<Impl>>),
+    /// `::highlight(foo)`
+    Highlight(Atom),
+    Custom(Impl::PseudoElement),
 }
 
 
-#[derive(PartialEq, Clone, Debug)]
-pub struct CompoundSelector {
-    pub simple_selectors: Vec<SimpleSelector>,
-    pub next: Option<(Box<CompoundSelector>, Combinator)>,  // c.next is left of c
+/// A complex selector, stored as one contiguous sequence of components in matching order: the
+/// simple selectors of each compound selector from the rightmost one to the leftmost one, with
+/// the combinator between two compound selectors in between them.
+///
+/// `a > b.c` is stored as `b`, `.c`, `>`, `a`.
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub struct ComplexSelector<Impl: SelectorImpl> {
+    components: Vec<Component<Impl>>,
 }
 
-#[derive(PartialEq, Clone, Copy, Debug)]
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub enum Component<Impl: SelectorImpl> {
+    SimpleSelector(SimpleSelector<Impl>),
+    Combinator(Combinator),
+}
+
+impl<Impl: SelectorImpl> ComplexSelector<Impl> {
+    /// `rest` are the compound selectors to the left of `rightmost`, from right to left, each
+    /// with the combinator to its right.
+    pub fn new(rightmost: Vec<SimpleSelector<Impl>>,
+               rest: Vec<(Combinator, Vec<SimpleSelector<Impl>>)>)
+               -> ComplexSelector<Impl> {
+        let len = rest.iter().fold(rightmost.len(), |len, &(_, ref simple_selectors)| {
+            len + 1 + simple_selectors.len()
+        });
+        let mut components = Vec::with_capacity(len);
+        components.extend(rightmost.into_iter().map(Component::SimpleSelector));
+        for (combinator, simple_selectors) in rest.into_iter() {
+            components.push(Component::Combinator(combinator));
+            components.extend(simple_selectors.into_iter().map(Component::SimpleSelector));
+        }
+        ComplexSelector {
+            components: components,
+        }
+    }
+
+    pub fn rightmost_compound(&self) -> CompoundSelector<Impl> {
+        CompoundSelector {
+            components: &self.components,
+        }
+    }
+
+    pub fn components(&self) -> &[Component<Impl>] {
+        &self.components
+    }
+
+    /// The components in matching order, each of which can be rewritten but stays a simple
+    /// selector or a combinator, so that the compound selectors keep their boundaries.
+    pub fn components_mut(&mut self) -> ComponentsMut<Impl> {
+        ComponentsMut {
+            components: self.components.iter_mut(),
+        }
+    }
+}
+
+pub enum ComponentMut<'a, Impl: SelectorImpl + 'a> {
+    SimpleSelector(&'a mut SimpleSelector<Impl>),
+    Combinator(&'a mut Combinator),
+}
+
+/// The components of a `ComplexSelector`, by mutable reference.
+pub struct ComponentsMut<'a, Impl: SelectorImpl + 'a> {
+    components: slice::IterMut<'a, Component<Impl>>,
+}
+
+impl<'a, Impl: SelectorImpl> Iterator for ComponentsMut<'a, Impl> {
+    type Item = ComponentMut<'a, Impl>;
+
+    fn next(&mut self) -> Option<ComponentMut<'a, Impl>> {
+        self.components.next().map(|component| {
+            match *component {
+                Component::SimpleSelector(ref mut simple_selector) => {
+                    ComponentMut::SimpleSelector(simple_selector)
+                }
+                Component::Combinator(ref mut combinator) => ComponentMut::Combinator(combinator),
+            }
+        })
+    }
+}
+
+/// A compound selector in a `ComplexSelector`, from which the compound selectors to its left can
+/// be reached.
+pub struct CompoundSelector<'a, Impl: SelectorImpl + 'a> {
+    /// The components of this compound selector and of those to its left.
+    components: &'a [Component<Impl>],
+}
+
+// FIXME(https://github.com/rust-lang/rust/issues/7671)
+// derive(Clone, Copy) requires Impl: Clone + Copy.
+impl<'a, Impl: SelectorImpl> Clone for CompoundSelector<'a, Impl> {
+    fn clone(&self) -> CompoundSelector<'a, Impl> {
+        *self
+    }
+}
+
+impl<'a, Impl: SelectorImpl> Copy for CompoundSelector<'a, Impl> {}
+
+impl<'a, Impl: SelectorImpl> CompoundSelector<'a, Impl> {
+    pub fn simple_selectors(self) -> SimpleSelectors<'a, Impl> {
+        SimpleSelectors {
+            components: self.components,
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.simple_selectors().next().is_none()
+    }
+
+    /// The compound selector to the left of this one, and the combinator between them.
+    pub fn next(self) -> Option<(CompoundSelector<'a, Impl>, Combinator)> {
+        for (i, component) in self.components.iter().enumerate() {
+            match *component {
+                Component::Combinator(combinator) => {
+                    let next = CompoundSelector {
+                        components: &self.components[i + 1..],
+                    };
+                    return Some((next, combinator))
+                }
+                Component::SimpleSelector(..) => {}
+            }
+        }
+        None
+    }
+
+    /// The components of this compound selector and of those to its left.
+    pub fn components(self) -> &'a [Component<Impl>] {
+        self.components
+    }
+}
+
+/// The simple selectors of a `CompoundSelector`.
+pub struct SimpleSelectors<'a, Impl: SelectorImpl + 'a> {
+    components: &'a [Component<Impl>],
+}
+
+impl<'a, Impl: SelectorImpl> Iterator for SimpleSelectors<'a, Impl> {
+    type Item = &'a SimpleSelector<Impl>;
+
+    fn next(&mut self) -> Option<&'a SimpleSelector<Impl>> {
+        let components = self.components;
+        match components.first() {
+            Some(&Component::SimpleSelector(ref simple_selector)) => {
+                self.components = &components[1..];
+                Some(simple_selector)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Eq, PartialEq, Clone, Copy, Hash, Debug)]
 pub enum Combinator {
     Child,  //  >
     Descendant,  // space
@@ -62,49 +555,111 @@ pub enum Combinator {
 }
 
 #[derive(Eq, PartialEq, Clone, Hash, Debug)]
-pub enum SimpleSelector {
+pub enum SimpleSelector<Impl: SelectorImpl> {
     ID(Atom),
     Class(Atom),
     LocalName(LocalName),
-    Namespace(Namespace),
+    /// The prefix is `None` when the default namespace applies.
+    Namespace(Option<Atom>, Namespace),
+    /// `*|` when a default namespace is declared, so that it serializes back the same way.
+    ExplicitAnyNamespace,
 
     // Attribute selectors
     AttrExists(AttrSelector),  // [foo]
     AttrEqual(AttrSelector, String, CaseSensitivity),  // [foo=bar]
-    AttrIncludes(AttrSelector, String),  // [foo~=bar]
-    AttrDashMatch(AttrSelector, String, String), // [foo|=bar]  Second string is the first + "-"
-    AttrPrefixMatch(AttrSelector, String),  // [foo^=bar]
-    AttrSubstringMatch(AttrSelector, String),  // [foo*=bar]
-    AttrSuffixMatch(AttrSelector, String),  // [foo$=bar]
+    AttrIncludes(AttrSelector, String, CaseSensitivity),  // [foo~=bar]
+    AttrDashMatch(AttrSelector, String, CaseSensitivity), // [foo|=bar]
+    AttrPrefixMatch(AttrSelector, String, CaseSensitivity),  // [foo^=bar]
+    AttrSubstringMatch(AttrSelector, String, CaseSensitivity),  // [foo*=bar]
+    AttrSuffixMatch(AttrSelector, String, CaseSensitivity),  // [foo$=bar]
 
     // Pseudo-classes
-    Negation(Vec<SimpleSelector>),
+    /// `:not()`, whose arguments never have a pseudo-element.
+    Negation(Vec<Selector<Impl>>),
+    /// `:is()`, whose arguments never have a pseudo-element.
+    Is(Vec<Selector<Impl>>),
+    /// `:where()`, like `:is()` but with zero specificity.
+    Where(Vec<Selector<Impl>>),
+    Has(Vec<RelativeSelector<Impl>>),
     AnyLink,
     Link,
     Visited,
+    LocalLink,
+    Target,
+    Scope,
     Hover,
     Focus,
+    FocusWithin,
+    FocusVisible,
+    Active,
     Disabled,
     Enabled,
     Checked,
     Indeterminate,
+    Required,
+    Optional,
+    Valid,
+    Invalid,
+    ReadOnly,
+    ReadWrite,
+    PlaceholderShown,
+    Default,
+    InRange,
+    OutOfRange,
     FirstChild, LastChild, OnlyChild,
     Root,
     NthChild(i32, i32),
     NthLastChild(i32, i32),
+    /// `:nth-child(An+B of S)`, S never having a pseudo-element.
+    NthChildOf(i32, i32, Vec<Selector<Impl>>),
+    NthLastChildOf(i32, i32, Vec<Selector<Impl>>),
     NthOfType(i32, i32),
     NthLastOfType(i32, i32),
     FirstOfType,
     LastOfType,
     OnlyOfType,
-    ServoNonzeroBorder,
+    Empty,
+    Defined,
+    /// `:state()`, a custom element state.
+    State(Atom),
+    /// `:lang()`, with the language ranges as written.
+    Lang(Vec<String>),
+    Dir(Direction),
+    /// `:host`, or `:host()` with a compound selector argument.
+    Host(Option<Vec<SimpleSelector<Impl>>>),
+    /// `:host-context()`, whose argument is a single compound selector.
+    HostContext(Vec<SimpleSelector<Impl>>),
+    NonTSPseudoClass(Impl::NonTSPseudoClass),
     // ...
 }
 
 
+/// An argument of `:has()`, e.g. `> img`: the selector must match an element that relates with
+/// `combinator` to the element `:has()` is matched against.
+#[derive(Eq, PartialEq, Clone, Hash, Debug)]
+pub struct RelativeSelector<Impl: SelectorImpl> {
+    pub combinator: Combinator,
+    /// Never has a pseudo-element.
+    pub selector: Selector<Impl>,
+}
+
+
+/// The directionality of an element, as matched by `:dir()`.
+#[derive(Eq, PartialEq, Clone, Hash, Copy, Debug)]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+
+/// How an attribute selector compares values, as set by its `i` or `s` flag.
 #[derive(Eq, PartialEq, Clone, Hash, Copy, Debug)]
 pub enum CaseSensitivity {
-    CaseSensitive,  // Selectors spec says language-defined, but HTML says sensitive.
+    /// No flag: depends on the document language.
+    DocumentDefault,
+    /// `s`
+    CaseSensitive,
+    /// `i`: ASCII case-insensitive.
     CaseInsensitive,
 }
 
@@ -120,6 +675,8 @@ pub struct AttrSelector {
     pub name: Atom,
     pub lower_name: Atom,
     pub namespace: NamespaceConstraint,
+    /// The prefix of `namespace`, unless it is `NamespaceConstraint::Any` or the null namespace.
+    pub prefix: Option<Atom>,
 }
 
 #[derive(Eq, PartialEq, Clone, Hash, Debug)]
@@ -129,79 +686,497 @@ pub enum NamespaceConstraint {
 }
 
 
-fn compute_specificity(mut selector: &CompoundSelector,
-                       pseudo_element: &Option<PseudoElement>) -> u32 {
-    struct Specificity {
-        id_selectors: u32,
-        class_like_selectors: u32,
-        element_selectors: u32,
+impl<Impl: SelectorImpl> ToCss for Selector<Impl> {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        let compound = self.complex_selector.rightmost_compound();
+        if self.pseudo_element.is_some() && compound.is_empty() {
+            // `::before` rather than `*::before`
+            match compound.next() {
+                Some((next, combinator)) => {
+                    try!(next.to_css(dest));
+                    try!(combinator.to_css(dest));
+                }
+                None => (),
+            }
+        } else {
+            try!(compound.to_css(dest));
+        }
+        match self.pseudo_element {
+            Some(ref pseudo_element) => try!(pseudo_element.to_css(dest)),
+            None => (),
+        }
+        for simple_selector in self.pseudo_element_state.iter() {
+            try!(simple_selector.to_css(dest));
+        }
+        Ok(())
     }
-    let mut specificity = Specificity {
-        id_selectors: 0,
-        class_like_selectors: 0,
-        element_selectors: 0,
-    };
-    if pseudo_element.is_some() { specificity.element_selectors += 1 }
+}
 
-    simple_selectors_specificity(&selector.simple_selectors, &mut specificity);
-    loop {
-        match selector.next {
-            None => break,
-            Some((ref next_selector, _)) => {
-                selector = &**next_selector;
-                simple_selectors_specificity(&selector.simple_selectors, &mut specificity)
+impl<Impl: SelectorImpl> ToCss for PseudoElement<Impl> {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        match *self {
+            PseudoElement::Before => dest.write_str("::before"),
+            PseudoElement::After => dest.write_str("::after"),
+            PseudoElement::FirstLine => dest.write_str("::first-line"),
+            PseudoElement::FirstLetter => dest.write_str("::first-letter"),
+            PseudoElement::Selection => dest.write_str("::selection"),
+            PseudoElement::Placeholder => dest.write_str("::placeholder"),
+            PseudoElement::Marker => dest.write_str("::marker"),
+            PseudoElement::Backdrop => dest.write_str("::backdrop"),
+            PseudoElement::FileSelectorButton => dest.write_str("::file-selector-button"),
+            PseudoElement::Part(ref names) => {
+                try!(dest.write_str("::part("));
+                for (i, name) in names.iter().enumerate() {
+                    if i > 0 {
+                        try!(dest.write_str(" "));
+                    }
+                    try!(serialize_identifier(name, dest));
+                }
+                dest.write_str(")")
+            }
+            PseudoElement::Slotted(ref simple_selectors) => {
+                try!(dest.write_str("::slotted("));
+                try!(simple_selectors_to_css(simple_selectors, dest));
+                dest.write_str(")")
+            }
+            PseudoElement::Highlight(ref name) => {
+                try!(dest.write_str("::highlight("));
+                try!(serialize_identifier(name, dest));
+                dest.write_str(")")
+            }
+            PseudoElement::Custom(ref pseudo_element) => pseudo_element.to_css(dest),
+        }
+    }
+}
+
+/// Serializes the whole chain, `self` being the rightmost compound selector.
+impl<'a, Impl: SelectorImpl> ToCss for CompoundSelector<'a, Impl> {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        // From right to left, each with the combinator to its right.
+        let mut compounds = vec![(*self, None)];
+        let mut compound = *self;
+        while let Some((next, combinator)) = compound.next() {
+            compounds.push((next, Some(combinator)));
+            compound = next;
+        }
+        for &(compound, combinator) in compounds.iter().rev() {
+            try!(simple_selectors_to_css(compound.simple_selectors(), dest));
+            match combinator {
+                Some(combinator) => try!(combinator.to_css(dest)),
+                None => (),
             }
         }
+        Ok(())
+    }
+}
+
+impl ToCss for Combinator {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        dest.write_str(match *self {
+            Combinator::Child => " > ",
+            Combinator::Descendant => " ",
+            Combinator::NextSibling => " + ",
+            Combinator::LaterSibling => " ~ ",
+        })
     }
+}
+
+impl<Impl: SelectorImpl> ToCss for SimpleSelector<Impl> {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        match *self {
+            SimpleSelector::ID(ref id) => {
+                try!(dest.write_str("#"));
+                serialize_identifier(id, dest)
+            }
+            SimpleSelector::Class(ref class) => {
+                try!(dest.write_str("."));
+                serialize_identifier(class, dest)
+            }
+            SimpleSelector::LocalName(ref local_name) => {
+                serialize_identifier(&local_name.name, dest)
+            }
+            SimpleSelector::Namespace(None, _) => Ok(()),
+            SimpleSelector::Namespace(Some(ref prefix), _) => {
+                if !prefix.is_empty() {
+                    try!(serialize_identifier(prefix, dest));
+                }
+                dest.write_str("|")
+            }
+            SimpleSelector::ExplicitAnyNamespace => dest.write_str("*|"),
+
+            SimpleSelector::AttrExists(ref attr) => {
+                try!(dest.write_str("["));
+                try!(attr.to_css(dest));
+                dest.write_str("]")
+            }
+            SimpleSelector::AttrEqual(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "=", value, case_sensitivity, dest)
+            }
+            SimpleSelector::AttrIncludes(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "~=", value, case_sensitivity, dest)
+            }
+            SimpleSelector::AttrDashMatch(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "|=", value, case_sensitivity, dest)
+            }
+            SimpleSelector::AttrPrefixMatch(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "^=", value, case_sensitivity, dest)
+            }
+            SimpleSelector::AttrSubstringMatch(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "*=", value, case_sensitivity, dest)
+            }
+            SimpleSelector::AttrSuffixMatch(ref attr, ref value, case_sensitivity) => {
+                attr_to_css(attr, "$=", value, case_sensitivity, dest)
+            }
+
+            SimpleSelector::Negation(ref selectors) => {
+                try!(dest.write_str(":not("));
+                try!(serialize_selector_list(selectors, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::Is(ref selectors) => {
+                try!(dest.write_str(":is("));
+                try!(serialize_selector_list(selectors, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::Where(ref selectors) => {
+                try!(dest.write_str(":where("));
+                try!(serialize_selector_list(selectors, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::Has(ref relative_selectors) => {
+                try!(dest.write_str(":has("));
+                let mut first = true;
+                for relative_selector in relative_selectors.iter() {
+                    if !first {
+                        try!(dest.write_str(", "));
+                    }
+                    first = false;
+                    try!(relative_selector.to_css(dest));
+                }
+                dest.write_str(")")
+            }
+            SimpleSelector::AnyLink => dest.write_str(":any-link"),
+            SimpleSelector::Link => dest.write_str(":link"),
+            SimpleSelector::Visited => dest.write_str(":visited"),
+            SimpleSelector::LocalLink => dest.write_str(":local-link"),
+            SimpleSelector::Target => dest.write_str(":target"),
+            SimpleSelector::Scope => dest.write_str(":scope"),
+            SimpleSelector::Hover => dest.write_str(":hover"),
+            SimpleSelector::Focus => dest.write_str(":focus"),
+            SimpleSelector::FocusWithin => dest.write_str(":focus-within"),
+            SimpleSelector::FocusVisible => dest.write_str(":focus-visible"),
+            SimpleSelector::Active => dest.write_str(":active"),
+            SimpleSelector::Disabled => dest.write_str(":disabled"),
+            SimpleSelector::Enabled => dest.write_str(":enabled"),
+            SimpleSelector::Checked => dest.write_str(":checked"),
+            SimpleSelector::Indeterminate => dest.write_str(":indeterminate"),
+            SimpleSelector::Required => dest.write_str(":required"),
+            SimpleSelector::Optional => dest.write_str(":optional"),
+            SimpleSelector::Valid => dest.write_str(":valid"),
+            SimpleSelector::Invalid => dest.write_str(":invalid"),
+            SimpleSelector::ReadOnly => dest.write_str(":read-only"),
+            SimpleSelector::ReadWrite => dest.write_str(":read-write"),
+            SimpleSelector::PlaceholderShown => dest.write_str(":placeholder-shown"),
+            SimpleSelector::Default => dest.write_str(":default"),
+            SimpleSelector::InRange => dest.write_str(":in-range"),
+            SimpleSelector::OutOfRange => dest.write_str(":out-of-range"),
+            SimpleSelector::FirstChild => dest.write_str(":first-child"),
+            SimpleSelector::LastChild => dest.write_str(":last-child"),
+            SimpleSelector::OnlyChild => dest.write_str(":only-child"),
+            SimpleSelector::Root => dest.write_str(":root"),
+            SimpleSelector::NthChild(a, b) => nth_to_css("nth-child", a, b, None, dest),
+            SimpleSelector::NthLastChild(a, b) => nth_to_css("nth-last-child", a, b, None, dest),
+            SimpleSelector::NthChildOf(a, b, ref selectors) => {
+                nth_to_css("nth-child", a, b, Some(&**selectors), dest)
+            }
+            SimpleSelector::NthLastChildOf(a, b, ref selectors) => {
+                nth_to_css("nth-last-child", a, b, Some(&**selectors), dest)
+            }
+            SimpleSelector::NthOfType(a, b) => nth_to_css("nth-of-type", a, b, None, dest),
+            SimpleSelector::NthLastOfType(a, b) => nth_to_css("nth-last-of-type", a, b, None, dest),
+            SimpleSelector::FirstOfType => dest.write_str(":first-of-type"),
+            SimpleSelector::LastOfType => dest.write_str(":last-of-type"),
+            SimpleSelector::OnlyOfType => dest.write_str(":only-of-type"),
+            SimpleSelector::Empty => dest.write_str(":empty"),
+            SimpleSelector::Defined => dest.write_str(":defined"),
+            SimpleSelector::State(ref state) => {
+                try!(dest.write_str(":state("));
+                try!(serialize_identifier(state, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::Lang(ref ranges) => {
+                try!(dest.write_str(":lang("));
+                for (i, range) in ranges.iter().enumerate() {
+                    if i > 0 {
+                        try!(dest.write_str(", "));
+                    }
+                    if range.is_empty() || range.starts_with("*") {
+                        try!(serialize_string(range, dest));
+                    } else {
+                        try!(serialize_identifier(range, dest));
+                    }
+                }
+                dest.write_str(")")
+            }
+            SimpleSelector::Dir(Direction::Ltr) => dest.write_str(":dir(ltr)"),
+            SimpleSelector::Dir(Direction::Rtl) => dest.write_str(":dir(rtl)"),
+            SimpleSelector::Host(None) => dest.write_str(":host"),
+            SimpleSelector::Host(Some(ref simple_selectors)) => {
+                try!(dest.write_str(":host("));
+                try!(simple_selectors_to_css(simple_selectors, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::HostContext(ref simple_selectors) => {
+                try!(dest.write_str(":host-context("));
+                try!(simple_selectors_to_css(simple_selectors, dest));
+                dest.write_str(")")
+            }
+            SimpleSelector::NonTSPseudoClass(ref pseudo_class) => pseudo_class.to_css(dest),
+        }
+    }
+}
+
+impl<Impl: SelectorImpl> ToCss for RelativeSelector<Impl> {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        try!(dest.write_str(match self.combinator {
+            Combinator::Child => "> ",
+            Combinator::Descendant => "",
+            Combinator::NextSibling => "+ ",
+            Combinator::LaterSibling => "~ ",
+        }));
+        self.selector.to_css(dest)
+    }
+}
+
+impl ToCss for AttrSelector {
+    fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+        match (&self.prefix, &self.namespace) {
+            (&Some(ref prefix), _) => {
+                try!(serialize_identifier(prefix, dest));
+                try!(dest.write_str("|"));
+            }
+            (&None, &NamespaceConstraint::Any) => try!(dest.write_str("*|")),
+            (&None, &NamespaceConstraint::Specific(_)) => (),
+        }
+        serialize_identifier(&self.name, dest)
+    }
+}
+
+/// Serialize a comma-separated list of Selectors, as returned by `parse_selector_list`.
+pub fn serialize_selector_list<Impl, W>(selectors: &[Selector<Impl>], dest: &mut W)
+                                       -> fmt::Result
+where Impl: SelectorImpl, W: fmt::Write {
+    let mut first = true;
+    for selector in selectors.iter() {
+        if !first {
+            try!(dest.write_str(", "));
+        }
+        first = false;
+        try!(selector.to_css(dest));
+    }
+    Ok(())
+}
+
+/// A namespace prefix needs a local name or `*` after it,
+/// and an empty sequence is a lone universal selector.
+fn simple_selectors_to_css<'a, Impl, I, W>(simple_selectors: I, dest: &mut W) -> fmt::Result
+where Impl: SelectorImpl + 'a, I: IntoIterator<Item=&'a SimpleSelector<Impl>>, W: fmt::Write {
+    let mut simple_selectors = simple_selectors.into_iter().peekable();
+    if simple_selectors.peek().is_none() {
+        return dest.write_str("*")
+    }
+    while let Some(simple_selector) = simple_selectors.next() {
+        try!(simple_selector.to_css(dest));
+        match *simple_selector {
+            SimpleSelector::Namespace(..) | SimpleSelector::ExplicitAnyNamespace => {
+                match simple_selectors.peek() {
+                    Some(&&SimpleSelector::LocalName(..)) => (),
+                    _ => try!(dest.write_str("*")),
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn attr_to_css<W>(attr: &AttrSelector, operator: &str, value: &str,
+                  case_sensitivity: CaseSensitivity, dest: &mut W)
+                  -> fmt::Result where W: fmt::Write {
+    try!(dest.write_str("["));
+    try!(attr.to_css(dest));
+    try!(dest.write_str(operator));
+    try!(serialize_string(value, dest));
+    dest.write_str(match case_sensitivity {
+        CaseSensitivity::DocumentDefault => "]",
+        CaseSensitivity::CaseSensitive => " s]",
+        CaseSensitivity::CaseInsensitive => " i]",
+    })
+}
 
-    fn simple_selectors_specificity(simple_selectors: &[SimpleSelector],
-                                    specificity: &mut Specificity) {
+/// https://drafts.csswg.org/cssom/#serializing-selectors for `an+b`
+fn nth_to_css<Impl, W>(name: &str, a: i32, b: i32, selectors: Option<&[Selector<Impl>]>,
+                       dest: &mut W)
+                       -> fmt::Result where Impl: SelectorImpl, W: fmt::Write {
+    try!(write!(dest, ":{}(", name));
+    match a {
+        0 => try!(write!(dest, "{}", b)),
+        _ => {
+            match a {
+                1 => try!(dest.write_str("n")),
+                -1 => try!(dest.write_str("-n")),
+                _ => try!(write!(dest, "{}n", a)),
+            }
+            if b > 0 {
+                try!(write!(dest, "+{}", b));
+            } else if b < 0 {
+                try!(write!(dest, "{}", b));
+            }
+        }
+    }
+    match selectors {
+        Some(selectors) => {
+            try!(dest.write_str(" of "));
+            try!(serialize_selector_list(selectors, dest));
+        }
+        None => (),
+    }
+    dest.write_str(")")
+}
+
+
+/// The specificity of a selector made of these parts, for selectors that have been rewritten
+/// after parsing.
+pub fn compute_specificity<Impl: SelectorImpl>(selector: &ComplexSelector<Impl>,
+                                               pseudo_element: &Option<PseudoElement<Impl>>,
+                                               pseudo_element_state: &[SimpleSelector<Impl>])
+                                               -> Specificity {
+    let mut specificity = complex_selector_specificity(selector);
+    match *pseudo_element {
+        Some(PseudoElement::Slotted(ref simple_selectors)) => {
+            specificity.c += 1;
+            simple_selectors_specificity(simple_selectors, &mut specificity)
+        }
+        Some(_) => specificity.c += 1,
+        None => {}
+    }
+    simple_selectors_specificity(pseudo_element_state, &mut specificity);
+
+    // Not a `SelectorVisitor`, which visits every nested selector: only the most specific
+    // argument of `:is()`, `:not()` or `:has()` counts, and those of `:where()` don't.
+    fn complex_selector_specificity<Impl>(selector: &ComplexSelector<Impl>) -> Specificity
+                                          where Impl: SelectorImpl {
+        let mut specificity = Specificity::default();
+        for (compound_selector, _) in selector.rightmost_compound().compounds_right_to_left() {
+            for simple_selector in compound_selector.simple_selectors() {
+                simple_selector_specificity(simple_selector, &mut specificity)
+            }
+        }
+        specificity
+    }
+
+    fn simple_selectors_specificity<Impl>(simple_selectors: &[SimpleSelector<Impl>],
+                                          specificity: &mut Specificity)
+                                          where Impl: SelectorImpl {
         for simple_selector in simple_selectors.iter() {
-            match simple_selector {
-                &SimpleSelector::LocalName(..) =>
-                    specificity.element_selectors += 1,
-                &SimpleSelector::ID(..) =>
-                    specificity.id_selectors += 1,
-                &SimpleSelector::Class(..) |
-                &SimpleSelector::AttrExists(..) |
-                &SimpleSelector::AttrEqual(..) |
-                &SimpleSelector::AttrIncludes(..) |
-                &SimpleSelector::AttrDashMatch(..) |
-                &SimpleSelector::AttrPrefixMatch(..) |
-                &SimpleSelector::AttrSubstringMatch(..) |
-                &SimpleSelector::AttrSuffixMatch(..) |
-                &SimpleSelector::AnyLink | &SimpleSelector::Link |
-                &SimpleSelector::Visited | &SimpleSelector::Hover |
-                &SimpleSelector::Focus |
-                &SimpleSelector::Disabled | &SimpleSelector::Enabled |
-                &SimpleSelector::FirstChild | &SimpleSelector::LastChild |
-                &SimpleSelector::OnlyChild | &SimpleSelector::Root |
-                &SimpleSelector::Checked |
-                &SimpleSelector::Indeterminate |
-                &SimpleSelector::NthChild(..) |
-                &SimpleSelector::NthLastChild(..) |
-                &SimpleSelector::NthOfType(..) |
-                &SimpleSelector::NthLastOfType(..) |
-                &SimpleSelector::FirstOfType | &SimpleSelector::LastOfType |
-                &SimpleSelector::OnlyOfType |
-                &SimpleSelector::ServoNonzeroBorder =>
-                    specificity.class_like_selectors += 1,
-                &SimpleSelector::Namespace(..) => (),
-                &SimpleSelector::Negation(ref negated) =>
-                    simple_selectors_specificity(negated, specificity),
-            }
-        }
-    }
-
-    static MAX_10BIT: u32 = (1u32 << 10) - 1;
-    cmp::min(specificity.id_selectors, MAX_10BIT) << 20
-    | cmp::min(specificity.class_like_selectors, MAX_10BIT) << 10
-    | cmp::min(specificity.element_selectors, MAX_10BIT)
-}
-
-
-
-pub fn parse_author_origin_selector_list_from_str(input: &str) -> Result<Vec<Selector>, ()> {
+            simple_selector_specificity(simple_selector, specificity)
+        }
+    }
+
+    fn simple_selector_specificity<Impl>(simple_selector: &SimpleSelector<Impl>,
+                                         specificity: &mut Specificity)
+                                         where Impl: SelectorImpl {
+        match simple_selector {
+            &SimpleSelector::LocalName(..) =>
+                specificity.c += 1,
+            &SimpleSelector::ID(..) =>
+                specificity.a += 1,
+            &SimpleSelector::Class(..) |
+            &SimpleSelector::AttrExists(..) |
+            &SimpleSelector::AttrEqual(..) |
+            &SimpleSelector::AttrIncludes(..) |
+            &SimpleSelector::AttrDashMatch(..) |
+            &SimpleSelector::AttrPrefixMatch(..) |
+            &SimpleSelector::AttrSubstringMatch(..) |
+            &SimpleSelector::AttrSuffixMatch(..) |
+            &SimpleSelector::AnyLink | &SimpleSelector::Link |
+            &SimpleSelector::Visited | &SimpleSelector::Hover |
+            &SimpleSelector::LocalLink | &SimpleSelector::Target |
+            &SimpleSelector::Scope |
+            &SimpleSelector::Focus | &SimpleSelector::FocusWithin |
+            &SimpleSelector::FocusVisible | &SimpleSelector::Active |
+            &SimpleSelector::Disabled | &SimpleSelector::Enabled |
+            &SimpleSelector::FirstChild | &SimpleSelector::LastChild |
+            &SimpleSelector::OnlyChild | &SimpleSelector::Root |
+            &SimpleSelector::Checked |
+            &SimpleSelector::Indeterminate |
+            &SimpleSelector::Required | &SimpleSelector::Optional |
+            &SimpleSelector::Valid | &SimpleSelector::Invalid |
+            &SimpleSelector::ReadOnly | &SimpleSelector::ReadWrite |
+            &SimpleSelector::PlaceholderShown | &SimpleSelector::Default |
+            &SimpleSelector::InRange | &SimpleSelector::OutOfRange |
+            &SimpleSelector::NthChild(..) |
+            &SimpleSelector::NthLastChild(..) |
+            &SimpleSelector::NthOfType(..) |
+            &SimpleSelector::NthLastOfType(..) |
+            &SimpleSelector::FirstOfType | &SimpleSelector::LastOfType |
+            &SimpleSelector::OnlyOfType | &SimpleSelector::Empty |
+            &SimpleSelector::Defined | &SimpleSelector::State(..) |
+            &SimpleSelector::Lang(..) | &SimpleSelector::Dir(..) |
+            &SimpleSelector::Host(None) |
+            &SimpleSelector::NonTSPseudoClass(..) =>
+                specificity.b += 1,
+            &SimpleSelector::Namespace(..) |
+            &SimpleSelector::ExplicitAnyNamespace |
+            &SimpleSelector::Where(..) => (),
+            &SimpleSelector::Negation(ref selectors) |
+            &SimpleSelector::Is(ref selectors) => {
+                // The specificity of the most specific argument.
+                add_max_specificity(selectors.iter().map(|selector| {
+                    complex_selector_specificity(&selector.complex_selector)
+                }), specificity)
+            }
+            &SimpleSelector::NthChildOf(_, _, ref selectors) |
+            &SimpleSelector::NthLastChildOf(_, _, ref selectors) => {
+                // Like `:nth-child()`, plus the most specific selector of the list.
+                specificity.b += 1;
+                add_max_specificity(selectors.iter().map(|selector| {
+                    complex_selector_specificity(&selector.complex_selector)
+                }), specificity)
+            }
+            &SimpleSelector::Host(Some(ref simple_selectors)) |
+            &SimpleSelector::HostContext(ref simple_selectors) => {
+                // Like `:host`, plus the argument.
+                specificity.b += 1;
+                simple_selectors_specificity(simple_selectors, specificity)
+            }
+            &SimpleSelector::Has(ref relative_selectors) => {
+                add_max_specificity(relative_selectors.iter().map(|relative_selector| {
+                    complex_selector_specificity(&relative_selector.selector.complex_selector)
+                }), specificity)
+            }
+        }
+    }
+
+    fn add_max_specificity<I>(specificities: I, specificity: &mut Specificity)
+    where I: Iterator<Item=Specificity> {
+        match specificities.max() {
+            Some(max) => {
+                specificity.a += max.a;
+                specificity.b += max.b;
+                specificity.c += max.c;
+            }
+            None => (),
+        }
+    }
+
+    specificity
+}
+
+
+
+pub fn parse_author_origin_selector_list_from_str<Impl: SelectorImpl>(input: &str)
+        -> Result<Vec<Selector<Impl>>, SelectorParseError> {
     let context = ParserContext::new();
     parse_selector_list(&context, &mut Parser::new(input))
 }
@@ -209,10 +1184,132 @@ pub fn parse_author_origin_selector_list_from_str(input: &str) -> Result<Vec<Sel
 /// Parse a comma-separated list of Selectors.
 /// aka Selector Group in http://www.w3.org/TR/css3-selectors/#grouping
 ///
-/// Return the Selectors or None if there is an invalid selector.
-pub fn parse_selector_list(context: &ParserContext, input: &mut Parser)
-                           -> Result<Vec<Selector>,()> {
-    input.parse_comma_separated(|input| parse_selector(context, input))
+/// Return the Selectors or the first error if there is an invalid selector.
+pub fn parse_selector_list<Impl: SelectorImpl>(context: &ParserContext, input: &mut Parser)
+                                               -> Result<Vec<Selector<Impl>>, SelectorParseError> {
+    let nesting = Nesting {
+        start: input.position(),
+        depth: 0,
+        in_has: false,
+    };
+    let selectors = try!(parse_selectors(context, input, |input| {
+        parse_selector(context, nesting, input)
+    }));
+    let location = input.current_source_location();
+    try!(check_input_length(context, nesting, input, location));
+    Ok(selectors)
+}
+
+/// Where the selector being parsed is, for the `ParserLimits` of the context.
+#[derive(Clone, Copy)]
+struct Nesting {
+    /// The start of the outermost selector list.
+    start: SourcePosition,
+    /// How many functional pseudo-classes and pseudo-elements the selector is nested in.
+    depth: usize,
+    /// Whether one of them is `:has()`.
+    in_has: bool,
+}
+
+impl Nesting {
+    /// For the argument of a functional pseudo-class or pseudo-element at `location`.
+    fn nested(self, context: &ParserContext, location: SourceLocation)
+              -> Result<Nesting, SelectorParseError> {
+        if self.depth >= context.limits.max_nesting_depth {
+            return Err(limit_exceeded(ParserLimit::NestingDepth, location))
+        }
+        Ok(Nesting {
+            start: self.start,
+            depth: self.depth + 1,
+            in_has: self.in_has,
+        })
+    }
+}
+
+fn limit_exceeded(limit: ParserLimit, location: SourceLocation) -> SelectorParseError {
+    SelectorParseError::new(SelectorParseErrorKind::LimitExceeded(limit), location)
+}
+
+/// Fails, reporting `location`, if the input parsed since the start of the outermost selector
+/// list is longer than the context allows. Checked after each simple selector, so that a long
+/// token is rejected as soon as it has been read.
+fn check_input_length(context: &ParserContext, nesting: Nesting, input: &Parser,
+                      location: SourceLocation)
+                      -> Result<(), SelectorParseError> {
+    if input.slice_from(nesting.start).len() > context.limits.max_input_length {
+        return Err(limit_exceeded(ParserLimit::InputLength, location))
+    }
+    Ok(())
+}
+
+/// Like `parse_comma_separated`, for a list of selectors: fails if there are more than the
+/// context allows.
+fn parse_selectors<T, F>(context: &ParserContext, input: &mut Parser, mut parse_one: F)
+                         -> Result<Vec<T>, SelectorParseError>
+where F: FnMut(&mut Parser) -> Result<T, SelectorParseError> {
+    let mut count = 0;
+    parse_comma_separated(input, |input| {
+        count += 1;
+        if count > context.limits.max_selectors_per_list {
+            skip_whitespace(input);
+            return Err(limit_exceeded(ParserLimit::SelectorsPerList,
+                                      input.current_source_location()))
+        }
+        parse_one(input)
+    })
+}
+
+
+/// Like `Parser::parse_comma_separated`, but keeps the error reported by `parse_one`.
+fn parse_comma_separated<T, F>(input: &mut Parser, mut parse_one: F)
+                               -> Result<Vec<T>, SelectorParseError>
+where F: FnMut(&mut Parser) -> Result<T, SelectorParseError> {
+    let location = input.current_source_location();
+    let mut error = None;
+    let result = input.parse_comma_separated(|input| {
+        parse_one(input).map_err(|e| error = Some(e))
+    });
+    result.map_err(|()| error.unwrap_or_else(|| {
+        SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken, location)
+    }))
+}
+
+/// Like `Parser::parse_nested_block`, but keeps the error reported by `parse`
+/// and reports any token left over in the block.
+fn parse_nested_block<T, F>(input: &mut Parser, parse: F) -> Result<T, SelectorParseError>
+where F: FnOnce(&mut Parser) -> Result<T, SelectorParseError> {
+    let location = input.current_source_location();
+    let mut error = None;
+    let result = input.parse_nested_block(|input| {
+        let result = parse(input).and_then(|value| {
+            try!(expect_exhausted(input));
+            Ok(value)
+        });
+        result.map_err(|e| error = Some(e))
+    });
+    result.map_err(|()| error.unwrap_or_else(|| {
+        SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken, location)
+    }))
+}
+
+fn expect_exhausted(input: &mut Parser) -> Result<(), SelectorParseError> {
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    if input.is_exhausted() {
+        Ok(())
+    } else {
+        Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken, location))
+    }
+}
+
+fn skip_whitespace(input: &mut Parser) {
+    loop {
+        let position = input.position();
+        if !matches!(input.next_including_whitespace(), Ok(Token::WhiteSpace(_))) {
+            input.reset(position);
+            break
+        }
+    }
 }
 
 
@@ -220,9 +1317,15 @@ pub fn parse_selector_list(context: &ParserContext, input: &mut Parser)
 /// selector : simple_selector_sequence [ combinator simple_selector_sequence ]* ;
 ///
 /// `Err` means invalid selector.
-fn parse_selector(context: &ParserContext, input: &mut Parser) -> Result<Selector,()> {
-    let (first, mut pseudo_element) = try!(parse_simple_selectors(context, input));
-    let mut compound = CompoundSelector{ simple_selectors: first, next: None };
+fn parse_selector<Impl: SelectorImpl>(context: &ParserContext, nesting: Nesting,
+                                      input: &mut Parser)
+                                      -> Result<Selector<Impl>, SelectorParseError> {
+    let (first, mut pseudo_element, mut pseudo_element_state) =
+        try!(parse_simple_selectors(context, nesting, input));
+    let mut compound = first;
+    // The compound selectors to the left of `compound`, from left to right, each with the
+    // combinator to its right.
+    let mut left = vec![];
 
     'outer_loop: while pseudo_element.is_none() {
         let combinator;
@@ -255,35 +1358,85 @@ fn parse_selector(context: &ParserContext, input: &mut Parser) -> Result<Selecto
                 }
             }
         }
-        let (simple_selectors, pseudo) = try!(parse_simple_selectors(context, input));
-        compound = CompoundSelector {
-            simple_selectors: simple_selectors,
-            next: Some((box compound, combinator))
+        // `left`, `compound` and the one after `combinator`.
+        if left.len() + 2 > context.limits.max_compound_selectors {
+            skip_whitespace(input);
+            return Err(limit_exceeded(ParserLimit::CompoundSelectors,
+                                      input.current_source_location()))
+        }
+        let result = parse_simple_selectors(context, nesting, input);
+        let (simple_selectors, pseudo, pseudo_state) = match result {
+            Err(SelectorParseError { kind: SelectorParseErrorKind::EmptySelector, location }) => {
+                let kind = if combinator == Combinator::Descendant {
+                    SelectorParseErrorKind::UnexpectedToken
+                } else {
+                    SelectorParseErrorKind::DanglingCombinator
+                };
+                return Err(SelectorParseError::new(kind, location))
+            }
+            result => try!(result),
         };
+        left.push((combinator, mem::replace(&mut compound, simple_selectors)));
         pseudo_element = pseudo;
+        pseudo_element_state = pseudo_state;
+    }
+
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    if !input.is_exhausted() {
+        let kind = if pseudo_element.is_some() {
+            SelectorParseErrorKind::PseudoElementNotLast
+        } else {
+            SelectorParseErrorKind::UnexpectedToken
+        };
+        return Err(SelectorParseError::new(kind, location))
     }
+
+    left.reverse();
+    let complex_selector = ComplexSelector::new(compound, left);
     Ok(Selector {
-        specificity: compute_specificity(&compound, &pseudo_element),
-        compound_selectors: Arc::new(compound),
+        specificity: compute_specificity(&complex_selector, &pseudo_element,
+                                         &pseudo_element_state),
+        complex_selector: Arc::new(complex_selector),
         pseudo_element: pseudo_element,
+        pseudo_element_state: pseudo_element_state,
     })
 }
 
 
-/// * `Err(())`: Invalid selector, abort
+/// * `Err(_)`: Invalid selector, abort
 /// * `Ok(None)`: Not a type selector, could be something else. `input` was not consumed.
-/// * `Ok(Some(vec))`: Length 0 (`*|*`), 1 (`*|E` or `ns|*`) or 2 (`|E` or `ns|E`)
-fn parse_type_selector(context: &ParserContext, input: &mut Parser)
-                       -> Result<Option<Vec<SimpleSelector>>, ()> {
+/// * `Ok(Some(vec))`: Length 0 (`*|*`), 1 (`*|E` or `ns|*`) or 2 (`|E` or `ns|E`),
+///   plus one for `*|` when there is a default namespace
+fn parse_type_selector<Impl: SelectorImpl>(context: &ParserContext, input: &mut Parser)
+                                           -> Result<Option<Vec<SimpleSelector<Impl>>>,
+                                                     SelectorParseError> {
     match try!(parse_qualified_name(context, input, /* in_attr_selector = */ false)) {
         None => Ok(None),
-        Some((namespace, local_name)) => {
+        Some((prefix, local_name)) => {
             let mut simple_selectors = vec!();
-            match namespace {
-                NamespaceConstraint::Specific(ns) => {
-                    simple_selectors.push(SimpleSelector::Namespace(ns))
-                },
-                NamespaceConstraint::Any => (),
+            match prefix {
+                NamespacePrefix::Implicit => {
+                    match context.default_namespace {
+                        Some(ref ns) => {
+                            simple_selectors.push(SimpleSelector::Namespace(None, ns.clone()))
+                        }
+                        None => (),
+                    }
+                }
+                NamespacePrefix::ExplicitNone => {
+                    simple_selectors.push(SimpleSelector::Namespace(Some(Atom::from_slice("")),
+                                                                    ns!("")))
+                }
+                NamespacePrefix::ExplicitAny => {
+                    // Without a default namespace, `*|E` and `E` mean the same thing.
+                    if context.default_namespace.is_some() {
+                        simple_selectors.push(SimpleSelector::ExplicitAnyNamespace)
+                    }
+                }
+                NamespacePrefix::ExplicitNamed(prefix, ns) => {
+                    simple_selectors.push(SimpleSelector::Namespace(Some(prefix), ns))
+                }
             }
             match local_name {
                 Some(name) => {
@@ -301,28 +1454,35 @@ fn parse_type_selector(context: &ParserContext, input: &mut Parser)
 
 
 #[derive(Debug)]
-enum SimpleSelectorParseResult {
-    SimpleSelector(SimpleSelector),
-    PseudoElement(PseudoElement),
+enum SimpleSelectorParseResult<Impl: SelectorImpl> {
+    SimpleSelector(SimpleSelector<Impl>),
+    PseudoElement(PseudoElement<Impl>),
+}
+
+
+/// The namespace part of a qualified name, as written.
+enum NamespacePrefix {
+    /// `E`
+    Implicit,
+    /// `|E`
+    ExplicitNone,
+    /// `*|E`
+    ExplicitAny,
+    /// `prefix|E`
+    ExplicitNamed(Atom, Namespace),
 }
 
 
-/// * `Err(())`: Invalid selector, abort
+/// * `Err(_)`: Invalid selector, abort
 /// * `Ok(None)`: Not a simple selector, could be something else. `input` was not consumed.
-/// * `Ok(Some((namespace, local_name)))`: `None` for the local name means a `*` universal selector
+/// * `Ok(Some((prefix, local_name)))`: `None` for the local name means a `*` universal selector
 fn parse_qualified_name<'i, 't>
                        (context: &ParserContext, input: &mut Parser<'i, 't>,
                         in_attr_selector: bool)
-                        -> Result<Option<(NamespaceConstraint, Option<Cow<'i, str>>)>, ()> {
-    let default_namespace = |local_name| {
-        let namespace = match context.default_namespace {
-            Some(ref ns) => NamespaceConstraint::Specific(ns.clone()),
-            None => NamespaceConstraint::Any,
-        };
-        Ok(Some((namespace, local_name)))
-    };
-
+                        -> Result<Option<(NamespacePrefix, Option<Cow<'i, str>>)>,
+                                  SelectorParseError> {
     let explicit_namespace = |input: &mut Parser<'i, 't>, namespace| {
+        let location = input.current_source_location();
         match input.next_including_whitespace() {
             Ok(Token::Delim('*')) if !in_attr_selector => {
                 Ok(Some((namespace, None)))
@@ -330,45 +1490,51 @@ fn parse_qualified_name<'i, 't>
             Ok(Token::Ident(local_name)) => {
                 Ok(Some((namespace, Some(local_name))))
             },
-            _ => Err(()),
+            _ => Err(SelectorParseError::new(SelectorParseErrorKind::ExpectedLocalName,
+                                             location)),
         }
     };
 
     let position = input.position();
+    let location = input.current_source_location();
     match input.next_including_whitespace() {
         Ok(Token::Ident(value)) => {
             let position = input.position();
             match input.next_including_whitespace() {
                 Ok(Token::Delim('|')) => {
-                    let result = context.namespace_prefixes.get(&*value);
-                    let namespace = try!(result.ok_or(()));
-                    explicit_namespace(input, NamespaceConstraint::Specific(namespace.clone()))
+                    let namespace = match context.namespace_for_prefix(&value) {
+                        Some(namespace) => namespace.clone(),
+                        None => {
+                            let kind = SelectorParseErrorKind::UndeclaredNamespacePrefix(
+                                value.into_owned());
+                            return Err(SelectorParseError::new(kind, location))
+                        }
+                    };
+                    let prefix = Atom::from_slice(&value);
+                    explicit_namespace(input, NamespacePrefix::ExplicitNamed(prefix, namespace))
                 },
                 _ => {
                     input.reset(position);
-                    if in_attr_selector {
-                        Ok(Some((NamespaceConstraint::Specific(ns!("")), Some(value))))
-                    } else {
-                        default_namespace(Some(value))
-                    }
+                    Ok(Some((NamespacePrefix::Implicit, Some(value))))
                 }
             }
         },
         Ok(Token::Delim('*')) => {
             let position = input.position();
             match input.next_including_whitespace() {
-                Ok(Token::Delim('|')) => explicit_namespace(input, NamespaceConstraint::Any),
+                Ok(Token::Delim('|')) => explicit_namespace(input, NamespacePrefix::ExplicitAny),
                 _ => {
                     input.reset(position);
                     if in_attr_selector {
-                        Err(())
+                        Err(SelectorParseError::new(SelectorParseErrorKind::ExpectedAttributeName,
+                                                    location))
                     } else {
-                        default_namespace(None)
+                        Ok(Some((NamespacePrefix::Implicit, None)))
                     }
                 },
             }
         },
-        Ok(Token::Delim('|')) => explicit_namespace(input, NamespaceConstraint::Specific(ns!(""))),
+        Ok(Token::Delim('|')) => explicit_namespace(input, NamespacePrefix::ExplicitNone),
         _ => {
             input.reset(position);
             Ok(None)
@@ -377,22 +1543,46 @@ fn parse_qualified_name<'i, 't>
 }
 
 
-fn parse_attribute_selector(context: &ParserContext, input: &mut Parser)
-                            -> Result<SimpleSelector, ()> {
+fn parse_attribute_selector<Impl: SelectorImpl>(context: &ParserContext, input: &mut Parser)
+                                                -> Result<SimpleSelector<Impl>,
+                                                          SelectorParseError> {
+    skip_whitespace(input);
+    let location = input.current_source_location();
     let attr = match try!(parse_qualified_name(context, input, /* in_attr_selector = */ true)) {
-        None => return Err(()),
+        None => {
+            return Err(SelectorParseError::new(SelectorParseErrorKind::ExpectedAttributeName,
+                                               location))
+        }
         Some((_, None)) => unreachable!(),
-        Some((namespace, Some(local_name))) => AttrSelector {
-            namespace: namespace,
-            lower_name: Atom::from_slice(&local_name.to_ascii_lowercase()),
-            name: Atom::from_slice(&local_name),
-        },
+        Some((prefix, Some(local_name))) => {
+            // Default namespace does not apply to attribute selectors.
+            let (namespace, prefix) = match prefix {
+                NamespacePrefix::Implicit |
+                NamespacePrefix::ExplicitNone => (NamespaceConstraint::Specific(ns!("")), None),
+                NamespacePrefix::ExplicitAny => (NamespaceConstraint::Any, None),
+                NamespacePrefix::ExplicitNamed(prefix, ns) => {
+                    (NamespaceConstraint::Specific(ns), Some(prefix))
+                }
+            };
+            AttrSelector {
+                namespace: namespace,
+                prefix: prefix,
+                lower_name: Atom::from_slice(&local_name.to_ascii_lowercase()),
+                name: Atom::from_slice(&local_name),
+            }
+        }
     };
 
-    fn parse_value(input: &mut Parser) -> Result<String, ()> {
-        Ok((try!(input.expect_ident_or_string())).into_owned())
+    fn parse_value(input: &mut Parser) -> Result<String, SelectorParseError> {
+        let location = input.current_source_location();
+        match input.expect_ident_or_string() {
+            Ok(value) => Ok(value.into_owned()),
+            Err(()) => Err(SelectorParseError::new(SelectorParseErrorKind::ExpectedAttributeValue,
+                                                   location)),
+        }
     }
-    // TODO: deal with empty value or value containing whitespace (see spec)
+    skip_whitespace(input);
+    let location = input.current_source_location();
     match input.next() {
         // [foo]
         Err(()) => Ok(SimpleSelector::AttrExists(attr)),
@@ -404,56 +1594,138 @@ fn parse_attribute_selector(context: &ParserContext, input: &mut Parser)
         }
         // [foo~=bar]
         Ok(Token::IncludeMatch) => {
-            Ok(SimpleSelector::AttrIncludes(attr, try!(parse_value(input))))
+            Ok(SimpleSelector::AttrIncludes(attr, try!(parse_value(input)),
+                                            try!(parse_attribute_flags(input))))
         }
         // [foo|=bar]
         Ok(Token::DashMatch) => {
-            let value = try!(parse_value(input));
-            let dashing_value = format!("{:?}-", value);
-            Ok(SimpleSelector::AttrDashMatch(attr, value, dashing_value))
+            Ok(SimpleSelector::AttrDashMatch(attr, try!(parse_value(input)),
+                                             try!(parse_attribute_flags(input))))
         }
         // [foo^=bar]
         Ok(Token::PrefixMatch) => {
-            Ok(SimpleSelector::AttrPrefixMatch(attr, try!(parse_value(input))))
+            Ok(SimpleSelector::AttrPrefixMatch(attr, try!(parse_value(input)),
+                                               try!(parse_attribute_flags(input))))
         }
         // [foo*=bar]
         Ok(Token::SubstringMatch) => {
-            Ok(SimpleSelector::AttrSubstringMatch(attr, try!(parse_value(input))))
+            Ok(SimpleSelector::AttrSubstringMatch(attr, try!(parse_value(input)),
+                                                  try!(parse_attribute_flags(input))))
         }
         // [foo$=bar]
         Ok(Token::SuffixMatch) => {
-            Ok(SimpleSelector::AttrSuffixMatch(attr, try!(parse_value(input))))
+            Ok(SimpleSelector::AttrSuffixMatch(attr, try!(parse_value(input)),
+                                               try!(parse_attribute_flags(input))))
         }
-        _ => Err(())
+        _ => Err(SelectorParseError::new(SelectorParseErrorKind::BadAttributeOperator, location))
     }
 }
 
 
-fn parse_attribute_flags(input: &mut Parser) -> Result<CaseSensitivity, ()> {
+fn parse_attribute_flags(input: &mut Parser) -> Result<CaseSensitivity, SelectorParseError> {
+    skip_whitespace(input);
+    let location = input.current_source_location();
     match input.next() {
-        Err(()) => Ok(CaseSensitivity::CaseSensitive),
+        Err(()) => Ok(CaseSensitivity::DocumentDefault),
         Ok(Token::Ident(ref value)) if value.eq_ignore_ascii_case("i") => {
             Ok(CaseSensitivity::CaseInsensitive)
         }
-        _ => Err(())
+        Ok(Token::Ident(ref value)) if value.eq_ignore_ascii_case("s") => {
+            Ok(CaseSensitivity::CaseSensitive)
+        }
+        _ => Err(SelectorParseError::new(SelectorParseErrorKind::BadAttributeFlags, location))
     }
 }
 
 
-/// Level 3: Parse **one** simple_selector
-fn parse_negation(context: &ParserContext, input: &mut Parser) -> Result<SimpleSelector,()> {
-    match try!(parse_type_selector(context, input)) {
-        Some(type_selector) => Ok(SimpleSelector::Negation(type_selector)),
-        None => {
-            match try!(parse_one_simple_selector(context,
-                                                 input,
-                                                 /* inside_negation = */ true)) {
-                Some(SimpleSelectorParseResult::SimpleSelector(simple_selector)) => {
-                    Ok(SimpleSelector::Negation(vec![simple_selector]))
+/// Level 4: a comma-separated list of complex selectors, none of which has a pseudo-element.
+///
+/// https://drafts.csswg.org/selectors-4/#negation
+fn parse_negation<Impl: SelectorImpl>(context: &ParserContext, nesting: Nesting,
+                                      input: &mut Parser)
+                                      -> Result<SimpleSelector<Impl>, SelectorParseError> {
+    let selectors = try!(parse_selectors(context, input, |input| {
+        parse_selector_without_pseudo_element(context, nesting, input)
+    }));
+    Ok(SimpleSelector::Negation(selectors))
+}
+
+fn parse_selector_without_pseudo_element<Impl>(context: &ParserContext, nesting: Nesting,
+                                               input: &mut Parser)
+                                               -> Result<Selector<Impl>, SelectorParseError>
+                                               where Impl: SelectorImpl {
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    let selector = try!(parse_selector(context, nesting, input));
+    if selector.pseudo_element.is_some() {
+        return Err(SelectorParseError::new(SelectorParseErrorKind::PseudoElementInArgument,
+                                           location))
+    }
+    Ok(selector)
+}
+
+/// A selector optionally starting with a combinator, as in `:has(> img)`.
+///
+/// https://drafts.csswg.org/selectors-4/#relative
+fn parse_relative_selector<Impl: SelectorImpl>(context: &ParserContext, nesting: Nesting,
+                                               input: &mut Parser)
+                                               -> Result<RelativeSelector<Impl>,
+                                                         SelectorParseError> {
+    let position = input.position();
+    let combinator = match input.next() {
+        Ok(Token::Delim('>')) => Combinator::Child,
+        Ok(Token::Delim('+')) => Combinator::NextSibling,
+        Ok(Token::Delim('~')) => Combinator::LaterSibling,
+        _ => {
+            input.reset(position);
+            Combinator::Descendant
+        }
+    };
+    Ok(RelativeSelector {
+        combinator: combinator,
+        selector: try!(parse_selector_without_pseudo_element(context, nesting, input)),
+    })
+}
+
+/// Parse the argument of `:is()` or `:where()`, dropping invalid selectors (including those with
+/// a pseudo-element) instead of failing. Going past the `ParserLimits` still fails.
+///
+/// https://drafts.csswg.org/selectors-4/#forgiving-selector
+fn parse_forgiving_selector_list<Impl: SelectorImpl>(context: &ParserContext, nesting: Nesting,
+                                                     input: &mut Parser)
+                                                     -> Result<Vec<Selector<Impl>>,
+                                                               SelectorParseError> {
+    let mut selectors = vec![];
+    let mut count = 0;
+    loop {
+        count += 1;
+        if count > context.limits.max_selectors_per_list {
+            skip_whitespace(input);
+            return Err(limit_exceeded(ParserLimit::SelectorsPerList,
+                                      input.current_source_location()))
+        }
+        let mut error = None;
+        let result = input.parse_until_before(Delimiter::Comma, |input| {
+            parse_selector(context, nesting, input).map_err(|e| error = Some(e))
+        });
+        match result {
+            Ok(selector) => {
+                if selector.pseudo_element.is_none() {
+                    selectors.push(selector)
                 }
-                _ => Err(())
             }
-        },
+            Err(()) => match error {
+                Some(error @ SelectorParseError {
+                    kind: SelectorParseErrorKind::LimitExceeded(..), ..
+                }) => return Err(error),
+                _ => (),
+            },
+        }
+        match input.next() {
+            Err(()) => return Ok(selectors),
+            Ok(Token::Comma) => (),
+            Ok(_) => unreachable!(),
+        }
     }
 }
 
@@ -461,86 +1733,280 @@ fn parse_negation(context: &ParserContext, input: &mut Parser) -> Result<SimpleS
 /// : [ type_selector | universal ] [ HASH | class | attrib | pseudo | negation ]*
 /// | [ HASH | class | attrib | pseudo | negation ]+
 ///
-/// `Err(())` means invalid selector
-fn parse_simple_selectors(context: &ParserContext, input: &mut Parser)
-                          -> Result<(Vec<SimpleSelector>, Option<PseudoElement>),()> {
+/// `Err(_)` means invalid selector
+fn parse_simple_selectors<Impl: SelectorImpl>(context: &ParserContext, nesting: Nesting,
+                                              input: &mut Parser)
+                                              -> Result<(Vec<SimpleSelector<Impl>>,
+                                                         Option<PseudoElement<Impl>>,
+                                                         Vec<SimpleSelector<Impl>>),
+                                                        SelectorParseError> {
     // Consume any leading whitespace.
-    loop {
-        let position = input.position();
-        if !matches!(input.next_including_whitespace(), Ok(Token::WhiteSpace(_))) {
-            input.reset(position);
-            break
-        }
-    }
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    try!(check_input_length(context, nesting, input, location));
+    let max_simple_selectors = context.limits.max_simple_selectors_per_compound;
     let mut empty = true;
     let mut simple_selectors = match try!(parse_type_selector(context, input)) {
         None => vec![],
         Some(s) => { empty = false; s }
     };
+    try!(check_input_length(context, nesting, input, location));
+    if simple_selectors.len() > max_simple_selectors {
+        return Err(limit_exceeded(ParserLimit::SimpleSelectorsPerCompound, location))
+    }
 
     let mut pseudo_element = None;
     loop {
-        match try!(parse_one_simple_selector(context,
-                                             input,
-                                             /* inside_negation = */ false)) {
+        let location = input.current_source_location();
+        let len = match try!(parse_one_simple_selector(context, nesting, input)) {
             None => break,
             Some(SimpleSelectorParseResult::SimpleSelector(s)) => {
                 simple_selectors.push(s);
-                empty = false
+                simple_selectors.len()
             }
             Some(SimpleSelectorParseResult::PseudoElement(p)) => {
                 pseudo_element = Some(p);
-                empty = false;
-                break
+                // The pseudo-element counts as one.
+                simple_selectors.len() + 1
             }
+        };
+        empty = false;
+        try!(check_input_length(context, nesting, input, location));
+        if len > max_simple_selectors {
+            return Err(limit_exceeded(ParserLimit::SimpleSelectorsPerCompound, location))
+        }
+        if pseudo_element.is_some() {
+            break
         }
     }
     if empty {
         // An empty selector is invalid.
-        Err(())
-    } else {
-        Ok((simple_selectors, pseudo_element))
+        return Err(SelectorParseError::new(SelectorParseErrorKind::EmptySelector, location))
+    }
+
+    // https://drafts.csswg.org/selectors-4/#pseudo-element-states
+    let mut pseudo_element_state = vec![];
+    if pseudo_element.is_some() {
+        loop {
+            let location = input.current_source_location();
+            match try!(parse_one_simple_selector(context, nesting, input)) {
+                None => break,
+                Some(SimpleSelectorParseResult::SimpleSelector(ref s))
+                        if is_user_action_pseudo_class(s) => {
+                    pseudo_element_state.push(s.clone())
+                }
+                Some(_) => {
+                    return Err(SelectorParseError::new(
+                        SelectorParseErrorKind::PseudoElementNotLast, location))
+                }
+            }
+            try!(check_input_length(context, nesting, input, location));
+            if simple_selectors.len() + 1 + pseudo_element_state.len() > max_simple_selectors {
+                return Err(limit_exceeded(ParserLimit::SimpleSelectorsPerCompound, location))
+            }
+        }
+    }
+    Ok((simple_selectors, pseudo_element, pseudo_element_state))
+}
+
+/// Parses the argument of `::slotted()`, `:host()` or `:host-context()`: a compound selector
+/// without a pseudo-element.
+fn parse_compound_selector_argument<Impl: SelectorImpl>(context: &ParserContext,
+                                                        nesting: Nesting,
+                                                        input: &mut Parser)
+                                                        -> Result<Vec<SimpleSelector<Impl>>,
+                                                                  SelectorParseError> {
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    match try!(parse_simple_selectors(context, nesting, input)) {
+        (simple_selectors, None, _) => Ok(simple_selectors),
+        (_, Some(_), _) => {
+            Err(SelectorParseError::new(SelectorParseErrorKind::PseudoElementInArgument,
+                                        location))
+        }
+    }
+}
+
+/// The feature that the pseudo-class or pseudo-element `name` belongs to, if it can be disabled.
+fn feature_of(name: &str) -> ParserFeatures {
+    match_ignore_ascii_case! { name,
+        "has" => HAS_PSEUDO_CLASS,
+        "host" => SHADOW_DOM_SELECTORS,
+        "host-context" => SHADOW_DOM_SELECTORS,
+        "slotted" => SHADOW_DOM_SELECTORS,
+        "part" => SHADOW_DOM_SELECTORS,
+        "state" => CUSTOM_STATE_PSEUDO_CLASS
+        _ => ParserFeatures::empty()
+    }
+}
+
+/// Whether `selector` can follow a pseudo-element, as in `::before:hover`.
+fn is_user_action_pseudo_class<Impl: SelectorImpl>(selector: &SimpleSelector<Impl>) -> bool {
+    match *selector {
+        SimpleSelector::Hover | SimpleSelector::Focus | SimpleSelector::FocusWithin |
+        SimpleSelector::FocusVisible | SimpleSelector::Active => true,
+        _ => false,
     }
 }
 
-fn parse_functional_pseudo_class(context: &ParserContext,
-                                 input: &mut Parser,
-                                 name: &str,
-                                 inside_negation: bool)
-                                 -> Result<SimpleSelector,()> {
+fn parse_functional_pseudo_class<Impl: SelectorImpl>(context: &ParserContext,
+                                                     nesting: Nesting,
+                                                     input: &mut Parser,
+                                                     name: &str,
+                                                     location: SourceLocation)
+                                                     -> Result<SimpleSelector<Impl>,
+                                                               SelectorParseError> {
+    if !context.features.contains(feature_of(name)) {
+        let kind = SelectorParseErrorKind::UnknownPseudoClass(name.to_owned());
+        return Err(SelectorParseError::new(kind, location))
+    }
     match_ignore_ascii_case! { name,
-        "nth-child" => parse_nth_pseudo_class(input, SimpleSelector::NthChild),
+        "nth-child" => parse_nth_child_pseudo_class(context, nesting, input,
+                                                    SimpleSelector::NthChild,
+                                                    SimpleSelector::NthChildOf),
         "nth-of-type" => parse_nth_pseudo_class(input, SimpleSelector::NthOfType),
-        "nth-last-child" => parse_nth_pseudo_class(input, SimpleSelector::NthLastChild),
+        "nth-last-child" => parse_nth_child_pseudo_class(context, nesting, input,
+                                                         SimpleSelector::NthLastChild,
+                                                         SimpleSelector::NthLastChildOf),
         "nth-last-of-type" => parse_nth_pseudo_class(input, SimpleSelector::NthLastOfType),
-        "not" => {
-            if inside_negation {
-                Err(())
-            } else {
-                parse_negation(context, input)
+        "not" => parse_negation(context, nesting, input),
+        "is" => {
+            Ok(SimpleSelector::Is(try!(parse_forgiving_selector_list(context, nesting, input))))
+        },
+        "has" => {
+            // https://drafts.csswg.org/selectors-4/#relational
+            if nesting.in_has {
+                return Err(SelectorParseError::new(SelectorParseErrorKind::NestedHas, location))
+            }
+            let nesting = Nesting { in_has: true, ..nesting };
+            let relative_selectors = try!(parse_selectors(context, input, |input| {
+                parse_relative_selector(context, nesting, input)
+            }));
+            Ok(SimpleSelector::Has(relative_selectors))
+        },
+        "where" => {
+            Ok(SimpleSelector::Where(try!(parse_forgiving_selector_list(context, nesting, input))))
+        },
+        "lang" => parse_lang(input),
+        "state" => {
+            skip_whitespace(input);
+            let location = input.current_source_location();
+            match input.next() {
+                Ok(Token::Ident(state)) => Ok(SimpleSelector::State(Atom::from_slice(&state))),
+                _ => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                 location)),
+            }
+        },
+        "host" => {
+            let simple_selectors = try!(parse_compound_selector_argument(context, nesting, input));
+            Ok(SimpleSelector::Host(Some(simple_selectors)))
+        },
+        "host-context" => {
+            let simple_selectors = try!(parse_compound_selector_argument(context, nesting, input));
+            Ok(SimpleSelector::HostContext(simple_selectors))
+        },
+        "dir" => {
+            skip_whitespace(input);
+            let location = input.current_source_location();
+            match input.next() {
+                Ok(Token::Ident(ref value)) if value.eq_ignore_ascii_case("ltr") => {
+                    Ok(SimpleSelector::Dir(Direction::Ltr))
+                }
+                Ok(Token::Ident(ref value)) if value.eq_ignore_ascii_case("rtl") => {
+                    Ok(SimpleSelector::Dir(Direction::Rtl))
+                }
+                _ => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                 location)),
+            }
+        }
+        _ => {
+            match Impl::parse_non_ts_functional_pseudo_class(context, name, input) {
+                Ok(pseudo_class) => Ok(SimpleSelector::NonTSPseudoClass(pseudo_class)),
+                Err(()) => {
+                    let kind = SelectorParseErrorKind::UnknownPseudoClass(name.to_owned());
+                    Err(SelectorParseError::new(kind, location))
+                }
             }
         }
-        _ => Err(())
     }
 }
 
 
-fn parse_nth_pseudo_class<F>(input: &mut Parser, selector: F) -> Result<SimpleSelector, ()>
-where F: FnOnce(i32, i32) -> SimpleSelector {
-    let (a, b) = try!(parse_nth(input));
+/// A comma-separated list of language ranges, each an identifier or a string.
+///
+/// https://drafts.csswg.org/selectors-4/#the-lang-pseudo
+fn parse_lang<Impl: SelectorImpl>(input: &mut Parser)
+                                  -> Result<SimpleSelector<Impl>, SelectorParseError> {
+    let ranges = try!(parse_comma_separated(input, |input| {
+        skip_whitespace(input);
+        let location = input.current_source_location();
+        match input.expect_ident_or_string() {
+            Ok(range) => {
+                try!(expect_exhausted(input));
+                Ok(range.into_owned())
+            }
+            Err(()) => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                   location)),
+        }
+    }));
+    Ok(SimpleSelector::Lang(ranges))
+}
+
+
+fn parse_nth_pseudo_class<Impl, F>(input: &mut Parser, selector: F)
+                                   -> Result<SimpleSelector<Impl>, SelectorParseError>
+where Impl: SelectorImpl, F: FnOnce(i32, i32) -> SimpleSelector<Impl> {
+    let (a, b) = try!(parse_an_plus_b(input));
     Ok(selector(a, b))
 }
 
+/// `An+B`, optionally followed by `of` and a list of complex selectors.
+///
+/// https://drafts.csswg.org/selectors-4/#the-nth-child-pseudo
+fn parse_nth_child_pseudo_class<Impl, F, G>(context: &ParserContext,
+                                            nesting: Nesting,
+                                            input: &mut Parser,
+                                            selector: F,
+                                            selector_of: G)
+                                            -> Result<SimpleSelector<Impl>, SelectorParseError>
+where Impl: SelectorImpl,
+      F: FnOnce(i32, i32) -> SimpleSelector<Impl>,
+      G: FnOnce(i32, i32, Vec<Selector<Impl>>) -> SimpleSelector<Impl> {
+    let (a, b) = try!(parse_an_plus_b(input));
+    let position = input.position();
+    match input.next() {
+        Ok(Token::Ident(ref value)) if value.eq_ignore_ascii_case("of") => {
+            let selectors = try!(parse_selectors(context, input, |input| {
+                parse_selector_without_pseudo_element(context, nesting, input)
+            }));
+            Ok(selector_of(a, b, selectors))
+        }
+        _ => {
+            input.reset(position);
+            Ok(selector(a, b))
+        }
+    }
+}
+
+fn parse_an_plus_b(input: &mut Parser) -> Result<(i32, i32), SelectorParseError> {
+    skip_whitespace(input);
+    let location = input.current_source_location();
+    parse_nth(input).map_err(|()| {
+        SelectorParseError::new(SelectorParseErrorKind::InvalidNth, location)
+    })
+}
+
 
 /// Parse a simple selector other than a type selector.
 ///
-/// * `Err(())`: Invalid selector, abort
+/// * `Err(_)`: Invalid selector, abort
 /// * `Ok(None)`: Not a simple selector, could be something else. `input` was not consumed.
 /// * `Ok(Some(_))`: Parsed a simple selector or pseudo-element
-fn parse_one_simple_selector(context: &ParserContext,
-                             input: &mut Parser,
-                             inside_negation: bool)
-                             -> Result<Option<SimpleSelectorParseResult>,()> {
+fn parse_one_simple_selector<Impl: SelectorImpl>(context: &ParserContext,
+                                                 nesting: Nesting,
+                                                 input: &mut Parser)
+                                                 -> Result<Option<SimpleSelectorParseResult<Impl>>,
+                                                           SelectorParseError> {
     let start_position = input.position();
     match input.next_including_whitespace() {
         Ok(Token::IDHash(id)) => {
@@ -548,21 +2014,24 @@ fn parse_one_simple_selector(context: &ParserContext,
             Ok(Some(SimpleSelectorParseResult::SimpleSelector(id)))
         }
         Ok(Token::Delim('.')) => {
+            let location = input.current_source_location();
             match input.next_including_whitespace() {
                 Ok(Token::Ident(class)) => {
                     let class = SimpleSelector::Class(Atom::from_slice(&class));
                     Ok(Some(SimpleSelectorParseResult::SimpleSelector(class)))
                 }
-                _ => Err(()),
+                _ => Err(SelectorParseError::new(SelectorParseErrorKind::ExpectedClassName,
+                                                 location)),
             }
         }
         Ok(Token::SquareBracketBlock) => {
-            let attr = try!(input.parse_nested_block(|input| {
+            let attr = try!(parse_nested_block(input, |input| {
                 parse_attribute_selector(context, input)
             }));
             Ok(Some(SimpleSelectorParseResult::SimpleSelector(attr)))
         }
         Ok(Token::Colon) => {
+            let location = input.current_source_location();
             match input.next_including_whitespace() {
                 Ok(Token::Ident(name)) => {
                     match parse_simple_pseudo_class(context, &name) {
@@ -572,9 +2041,13 @@ fn parse_one_simple_selector(context: &ParserContext,
                                 // ** Do not add to this list! **
                                 "before" => PseudoElement::Before,
                                 "after" => PseudoElement::After,
-                                "first-line" => return Err(()),
-                                "first-letter" => return Err(())
-                                _ => return Err(())
+                                "first-line" => PseudoElement::FirstLine,
+                                "first-letter" => PseudoElement::FirstLetter
+                                _ => {
+                                    let kind = SelectorParseErrorKind::UnknownPseudoClass(
+                                        name.to_string());
+                                    return Err(SelectorParseError::new(kind, location))
+                                }
                             };
                             Ok(Some(SimpleSelectorParseResult::PseudoElement(pseudo_element)))
                         },
@@ -582,21 +2055,41 @@ fn parse_one_simple_selector(context: &ParserContext,
                     }
                 }
                 Ok(Token::Function(name)) => {
-                    let pseudo = try!(input.parse_nested_block(|input| {
-                        parse_functional_pseudo_class(context, input, &name, inside_negation)
+                    let nesting = try!(nesting.nested(context, location));
+                    let pseudo = try!(parse_nested_block(input, |input| {
+                        parse_functional_pseudo_class(context, nesting, input, &name, location)
                     }));
                     Ok(Some(SimpleSelectorParseResult::SimpleSelector(pseudo)))
                 }
                 Ok(Token::Colon) => {
-                    match input.next() {
+                    let location = input.current_source_location();
+                    match input.next_including_whitespace() {
                         Ok(Token::Ident(name)) => {
-                            let pseudo = try!(parse_pseudo_element(&name));
+                            match parse_pseudo_element(context, &name) {
+                                Ok(pseudo) => {
+                                    Ok(Some(SimpleSelectorParseResult::PseudoElement(pseudo)))
+                                }
+                                Err(()) => {
+                                    let kind = SelectorParseErrorKind::UnknownPseudoElement(
+                                        name.to_string());
+                                    Err(SelectorParseError::new(kind, location))
+                                }
+                            }
+                        }
+                        Ok(Token::Function(name)) => {
+                            let nesting = try!(nesting.nested(context, location));
+                            let pseudo = try!(parse_nested_block(input, |input| {
+                                parse_functional_pseudo_element(context, nesting, input, &name,
+                                                                location)
+                            }));
                             Ok(Some(SimpleSelectorParseResult::PseudoElement(pseudo)))
                         }
-                        _ => Err(())
+                        _ => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                         location))
                     }
                 }
-                _ => Err(())
+                _ => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                 location))
             }
         }
         _ => {
@@ -606,17 +2099,37 @@ fn parse_one_simple_selector(context: &ParserContext,
     }
 }
 
-fn parse_simple_pseudo_class(context: &ParserContext, name: &str) -> Result<SimpleSelector,()> {
+fn parse_simple_pseudo_class<Impl: SelectorImpl>(context: &ParserContext, name: &str)
+                                                 -> Result<SimpleSelector<Impl>, ()> {
+    if !context.features.contains(feature_of(name)) {
+        return Err(())
+    }
     match_ignore_ascii_case! { name,
         "any-link" => Ok(SimpleSelector::AnyLink),
         "link" => Ok(SimpleSelector::Link),
         "visited" => Ok(SimpleSelector::Visited),
+        "local-link" => Ok(SimpleSelector::LocalLink),
+        "target" => Ok(SimpleSelector::Target),
+        "scope" => Ok(SimpleSelector::Scope),
         "hover" => Ok(SimpleSelector::Hover),
         "focus" => Ok(SimpleSelector::Focus),
+        "focus-within" => Ok(SimpleSelector::FocusWithin),
+        "focus-visible" => Ok(SimpleSelector::FocusVisible),
+        "active" => Ok(SimpleSelector::Active),
         "disabled" => Ok(SimpleSelector::Disabled),
         "enabled" => Ok(SimpleSelector::Enabled),
         "checked" => Ok(SimpleSelector::Checked),
         "indeterminate" => Ok(SimpleSelector::Indeterminate),
+        "required" => Ok(SimpleSelector::Required),
+        "optional" => Ok(SimpleSelector::Optional),
+        "valid" => Ok(SimpleSelector::Valid),
+        "invalid" => Ok(SimpleSelector::Invalid),
+        "read-only" => Ok(SimpleSelector::ReadOnly),
+        "read-write" => Ok(SimpleSelector::ReadWrite),
+        "placeholder-shown" => Ok(SimpleSelector::PlaceholderShown),
+        "default" => Ok(SimpleSelector::Default),
+        "in-range" => Ok(SimpleSelector::InRange),
+        "out-of-range" => Ok(SimpleSelector::OutOfRange),
         "first-child" => Ok(SimpleSelector::FirstChild),
         "last-child"  => Ok(SimpleSelector::LastChild),
         "only-child"  => Ok(SimpleSelector::OnlyChild),
@@ -624,180 +2137,801 @@ fn parse_simple_pseudo_class(context: &ParserContext, name: &str) -> Result<Simp
         "first-of-type" => Ok(SimpleSelector::FirstOfType),
         "last-of-type"  => Ok(SimpleSelector::LastOfType),
         "only-of-type"  => Ok(SimpleSelector::OnlyOfType),
-        "-servo-nonzero-border" => {
-            if context.in_user_agent_stylesheet {
-                Ok(SimpleSelector::ServoNonzeroBorder)
-            } else {
-                Err(())
-            }
-        }
-        _ => Err(())
+        "empty" => Ok(SimpleSelector::Empty),
+        "defined" => Ok(SimpleSelector::Defined),
+        "host" => Ok(SimpleSelector::Host(None))
+        _ => Impl::parse_non_ts_pseudo_class(context, name).map(SimpleSelector::NonTSPseudoClass)
     }
 }
 
-fn parse_pseudo_element(name: &str) -> Result<PseudoElement, ()> {
+fn parse_pseudo_element<Impl: SelectorImpl>(context: &ParserContext, name: &str)
+                                            -> Result<PseudoElement<Impl>, ()> {
     match_ignore_ascii_case! { name,
         "before" => Ok(PseudoElement::Before),
-        "after" => Ok(PseudoElement::After)
-        _ => Err(())
+        "after" => Ok(PseudoElement::After),
+        "first-line" => Ok(PseudoElement::FirstLine),
+        "first-letter" => Ok(PseudoElement::FirstLetter),
+        "selection" => Ok(PseudoElement::Selection),
+        "placeholder" => Ok(PseudoElement::Placeholder),
+        "marker" => Ok(PseudoElement::Marker),
+        "backdrop" => Ok(PseudoElement::Backdrop),
+        "file-selector-button" => Ok(PseudoElement::FileSelectorButton)
+        _ => Impl::parse_pseudo_element(context, name).map(PseudoElement::Custom)
+    }
+}
+
+fn parse_functional_pseudo_element<Impl: SelectorImpl>(context: &ParserContext,
+                                                       nesting: Nesting,
+                                                       input: &mut Parser,
+                                                       name: &str,
+                                                       location: SourceLocation)
+                                                       -> Result<PseudoElement<Impl>,
+                                                                 SelectorParseError> {
+    if !context.features.contains(feature_of(name)) {
+        let kind = SelectorParseErrorKind::UnknownPseudoElement(name.to_owned());
+        return Err(SelectorParseError::new(kind, location))
+    }
+    match_ignore_ascii_case! { name,
+        "part" => {
+            // `::part(<ident>+)`
+            let mut names = vec![];
+            loop {
+                skip_whitespace(input);
+                let location = input.current_source_location();
+                match input.next() {
+                    Ok(Token::Ident(part_name)) => names.push(Atom::from_slice(&part_name)),
+                    Err(()) if !names.is_empty() => return Ok(PseudoElement::Part(names)),
+                    _ => {
+                        return Err(SelectorParseError::new(
+                            SelectorParseErrorKind::UnexpectedToken, location))
+                    }
+                }
+            }
+        },
+        "slotted" => {
+            let simple_selectors = try!(parse_compound_selector_argument(context, nesting, input));
+            Ok(PseudoElement::Slotted(simple_selectors))
+        },
+        "highlight" => {
+            skip_whitespace(input);
+            let location = input.current_source_location();
+            match input.next() {
+                Ok(Token::Ident(highlight_name)) => {
+                    Ok(PseudoElement::Highlight(Atom::from_slice(&highlight_name)))
+                }
+                _ => Err(SelectorParseError::new(SelectorParseErrorKind::UnexpectedToken,
+                                                 location)),
+            }
+        }
+        _ => {
+            match Impl::parse_functional_pseudo_element(context, name, input) {
+                Ok(pseudo_element) => Ok(PseudoElement::Custom(pseudo_element)),
+                Err(()) => {
+                    let kind = SelectorParseErrorKind::UnknownPseudoElement(name.to_owned());
+                    Err(SelectorParseError::new(kind, location))
+                }
+            }
+        }
     }
 }
 
 
 #[cfg(test)]
 mod tests {
+    use std::fmt;
     use std::sync::Arc;
-    use cssparser::Parser;
+    use cssparser::{Parser, ToCss, serialize_identifier};
     use string_cache::Atom;
     use super::*;
 
-    fn parse(input: &str) -> Result<Vec<Selector>, ()> {
+    #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+    struct DummySelectorImpl;
+
+    #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+    enum DummyPseudoClass {
+        ServoNonzeroBorder,
+        ServoCaseSensitiveTypeAttr(Atom),
+    }
+
+    #[derive(PartialEq, Eq, Clone, Hash, Debug)]
+    enum DummyPseudoElement {
+        DetailsSummary,
+    }
+
+    impl SelectorImpl for DummySelectorImpl {
+        type NonTSPseudoClass = DummyPseudoClass;
+        type PseudoElement = DummyPseudoElement;
+
+        fn parse_non_ts_pseudo_class(context: &ParserContext, name: &str)
+                                     -> Result<DummyPseudoClass, ()> {
+            match_ignore_ascii_case! { name,
+                "-servo-nonzero-border" => {
+                    if context.in_user_agent_stylesheet() {
+                        Ok(DummyPseudoClass::ServoNonzeroBorder)
+                    } else {
+                        Err(())
+                    }
+                }
+                _ => Err(())
+            }
+        }
+
+        fn parse_non_ts_functional_pseudo_class(_context: &ParserContext, name: &str,
+                                                arguments: &mut Parser)
+                                                -> Result<DummyPseudoClass, ()> {
+            match_ignore_ascii_case! { name,
+                "-servo-case-sensitive-type-attr" => {
+                    let value = try!(arguments.expect_ident());
+                    Ok(DummyPseudoClass::ServoCaseSensitiveTypeAttr(Atom::from_slice(&value)))
+                }
+                _ => Err(())
+            }
+        }
+
+        fn parse_pseudo_element(_context: &ParserContext, name: &str)
+                                -> Result<DummyPseudoElement, ()> {
+            match_ignore_ascii_case! { name,
+                "-servo-details-summary" => Ok(DummyPseudoElement::DetailsSummary)
+                _ => Err(())
+            }
+        }
+    }
+
+    impl ToCss for DummyPseudoClass {
+        fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+            match *self {
+                DummyPseudoClass::ServoNonzeroBorder => dest.write_str(":-servo-nonzero-border"),
+                DummyPseudoClass::ServoCaseSensitiveTypeAttr(ref value) => {
+                    try!(dest.write_str(":-servo-case-sensitive-type-attr("));
+                    try!(serialize_identifier(value, dest));
+                    dest.write_str(")")
+                }
+            }
+        }
+    }
+
+    impl ToCss for DummyPseudoElement {
+        fn to_css<W>(&self, dest: &mut W) -> fmt::Result where W: fmt::Write {
+            match *self {
+                DummyPseudoElement::DetailsSummary => dest.write_str("::-servo-details-summary"),
+            }
+        }
+    }
+
+    fn parse(input: &str) -> Result<Vec<Selector<DummySelectorImpl>>, SelectorParseError> {
         parse_ns(input, &ParserContext::new())
     }
 
-    fn parse_ns(input: &str, context: &ParserContext) -> Result<Vec<Selector>, ()> {
+    fn parse_ns(input: &str, context: &ParserContext)
+                -> Result<Vec<Selector<DummySelectorImpl>>, SelectorParseError> {
         parse_selector_list(context, &mut Parser::new(input))
     }
 
-    fn specificity(a: u32, b: u32, c: u32) -> u32 {
-        a << 20 | b << 10 | c
+    fn parse_unlimited(input: &str)
+                       -> Result<Vec<Selector<DummySelectorImpl>>, SelectorParseError> {
+        parse_ns(input, &ParserContextBuilder::new().limits(ParserLimits::unlimited()).build())
+    }
+
+    fn parse_error(input: &str) -> (SelectorParseErrorKind, usize, usize) {
+        let error = parse(input).unwrap_err();
+        (error.kind, error.location.line, error.location.column)
+    }
+
+    fn specificity(a: u32, b: u32, c: u32) -> Specificity {
+        Specificity::new(a, b, c)
+    }
+
+    /// The simple selectors of the rightmost compound selector.
+    fn rightmost(selector: &Selector<DummySelectorImpl>)
+                 -> Vec<SimpleSelector<DummySelectorImpl>> {
+        selector.complex_selector.rightmost_compound().simple_selectors().cloned().collect()
     }
 
     #[test]
     fn test_parsing() {
-        assert_eq!(parse(""), Err(())) ;
+        assert!(parse("").is_err()) ;
         assert_eq!(parse("EeÉ"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::LocalName(LocalName {
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::LocalName(LocalName {
                     name: Atom::from_slice("EeÉ"),
                     lower_name: Atom::from_slice("eeÉ") })),
-                next: None,
-            }),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(0, 0, 1),
         })));
         assert_eq!(parse(".foo"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::Class(Atom::from_slice("foo"))),
-                next: None,
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::Class(Atom::from_slice("foo"))),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(0, 1, 0),
         })));
         assert_eq!(parse("#bar"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::ID(Atom::from_slice("bar"))),
-                next: None,
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::ID(Atom::from_slice("bar"))),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(1, 0, 0),
         })));
         assert_eq!(parse("e.foo#bar"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::LocalName(LocalName {
-                                            name: Atom::from_slice("e"),
-                                            lower_name: Atom::from_slice("e") }),
-                                       SimpleSelector::Class(Atom::from_slice("foo")),
-                                       SimpleSelector::ID(Atom::from_slice("bar"))),
-                next: None,
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::LocalName(LocalName {
+                         name: Atom::from_slice("e"),
+                         lower_name: Atom::from_slice("e") }),
+                     SimpleSelector::Class(Atom::from_slice("foo")),
+                     SimpleSelector::ID(Atom::from_slice("bar"))),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(1, 1, 1),
         })));
         assert_eq!(parse("e.foo #bar"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::ID(Atom::from_slice("bar"))),
-                next: Some((box CompoundSelector {
-                    simple_selectors: vec!(SimpleSelector::LocalName(LocalName {
-                                                name: Atom::from_slice("e"),
-                                                lower_name: Atom::from_slice("e") }),
-                                           SimpleSelector::Class(Atom::from_slice("foo"))),
-                    next: None,
-                }, Combinator::Descendant)),
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::ID(Atom::from_slice("bar"))),
+                vec!((Combinator::Descendant,
+                      vec!(SimpleSelector::LocalName(LocalName {
+                               name: Atom::from_slice("e"),
+                               lower_name: Atom::from_slice("e") }),
+                           SimpleSelector::Class(Atom::from_slice("foo"))))))),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(1, 1, 1),
         })));
         // Default namespace does not apply to attribute selectors
         // https://github.com/mozilla/servo/pull/1652
-        let mut context = ParserContext::new();
-        assert_eq!(parse_ns("[Foo]", &context), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::AttrExists(AttrSelector {
+        let mut builder = ParserContextBuilder::new();
+        assert_eq!(parse_ns("[Foo]", &builder.build()), Ok(vec!(Selector {
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::AttrExists(AttrSelector {
                     name: Atom::from_slice("Foo"),
                     lower_name: Atom::from_slice("foo"),
                     namespace: NamespaceConstraint::Specific(ns!("")),
+                    prefix: None,
                 })),
-                next: None,
-            }),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(0, 1, 0),
         })));
         // Default namespace does not apply to attribute selectors
         // https://github.com/mozilla/servo/pull/1652
-        context.default_namespace = Some(ns!(MathML));
+        builder.add_namespace(None, ns!(MathML)).unwrap();
+        let context = builder.build();
         assert_eq!(parse_ns("[Foo]", &context), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(SimpleSelector::AttrExists(AttrSelector {
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(SimpleSelector::AttrExists(AttrSelector {
                     name: Atom::from_slice("Foo"),
                     lower_name: Atom::from_slice("foo"),
                     namespace: NamespaceConstraint::Specific(ns!("")),
+                    prefix: None,
                 })),
-                next: None,
-            }),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(0, 1, 0),
         })));
         // Default namespace does apply to type selectors
         assert_eq!(parse_ns("e", &context), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(
-                    SimpleSelector::Namespace(ns!(MathML)),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(
+                    SimpleSelector::Namespace(None, ns!(MathML)),
                     SimpleSelector::LocalName(LocalName {
                         name: Atom::from_slice("e"),
                         lower_name: Atom::from_slice("e") }),
                 ),
-                next: None,
-            }),
+                vec!())),
             pseudo_element: None,
+            pseudo_element_state: vec![],
             specificity: specificity(0, 0, 1),
         })));
         // https://github.com/mozilla/servo/issues/1723
         assert_eq!(parse("::before"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(),
-                next: None,
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(vec!(), vec!())),
             pseudo_element: Some(PseudoElement::Before),
+            pseudo_element_state: vec![],
             specificity: specificity(0, 0, 1),
         })));
         assert_eq!(parse("div :after"), Ok(vec!(Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec!(),
-                next: Some((box CompoundSelector {
-                    simple_selectors: vec!(SimpleSelector::LocalName(LocalName {
-                        name: atom!("div"),
-                        lower_name: atom!("div") })),
-                    next: None,
-                }, Combinator::Descendant)),
-            }),
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec!(),
+                vec!((Combinator::Descendant,
+                      vec!(SimpleSelector::LocalName(LocalName {
+                          name: atom!("div"),
+                          lower_name: atom!("div") })))))),
             pseudo_element: Some(PseudoElement::After),
+            pseudo_element_state: vec![],
             specificity: specificity(0, 0, 2),
         })));
         assert_eq!(parse("#d1 > .ok"), Ok(vec![Selector {
-            compound_selectors: Arc::new(CompoundSelector {
-                simple_selectors: vec![
+            complex_selector: Arc::new(ComplexSelector::new(
+                vec![
                     SimpleSelector::Class(Atom::from_slice("ok")),
                 ],
-                next: Some((box CompoundSelector {
-                    simple_selectors: vec![
-                        SimpleSelector::ID(Atom::from_slice("d1")),
-                    ],
-                    next: None,
-                }, Combinator::Child)),
-            }),
+                vec![(Combinator::Child, vec![
+                    SimpleSelector::ID(Atom::from_slice("d1")),
+                ])])),
             pseudo_element: None,
-            specificity: (1 << 20) + (1 << 10) + (0 << 0),
-        }]))
+            pseudo_element_state: vec![],
+            specificity: specificity(1, 1, 0),
+        }]));
+        let selectors = parse("a b > c").unwrap();
+        assert_eq!(selectors[0].complex_selector.components(), &[
+            Component::SimpleSelector(SimpleSelector::LocalName(LocalName {
+                name: atom!("c"), lower_name: atom!("c") })),
+            Component::Combinator(Combinator::Child),
+            Component::SimpleSelector(SimpleSelector::LocalName(LocalName {
+                name: atom!("b"), lower_name: atom!("b") })),
+            Component::Combinator(Combinator::Descendant),
+            Component::SimpleSelector(SimpleSelector::LocalName(LocalName {
+                name: atom!("a"), lower_name: atom!("a") })),
+        ][..]);
+    }
+
+    #[test]
+    fn test_negation() {
+        let selectors = parse(":not(.a, #b > c, :not(d))").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(1, 0, 1));
+        assert_eq!(rightmost(&selectors[0]),
+                   vec![SimpleSelector::Negation(parse(".a, #b > c, :not(d)").unwrap())]);
+        assert_eq!(parse("a:not(:not(.b))").unwrap()[0].specificity, specificity(0, 1, 1));
+        assert!(parse(":not()").is_err());
+    }
+
+    #[test]
+    fn test_nth_child_of() {
+        let selectors = parse(":nth-child(odd of :not(.hidden))").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 2, 0));
+        assert_eq!(rightmost(&selectors[0]),
+                   vec![SimpleSelector::NthChildOf(2, 1, parse(":not(.hidden)").unwrap())]);
+        let selectors = parse(":nth-last-child(-n+3 OF a, #b > c)").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(1, 1, 1));
+        assert_eq!(rightmost(&selectors[0]),
+                   vec![SimpleSelector::NthLastChildOf(-1, 3, parse("a, #b > c").unwrap())]);
+        assert_eq!(rightmost(&parse(":nth-child(2n)").unwrap()[0]),
+                   vec![SimpleSelector::NthChild(2, 0)]);
+        assert!(parse(":nth-child(2n of)").is_err());
+        assert!(parse(":nth-child(2n of ::before)").is_err());
+        assert!(parse(":nth-of-type(2n of a)").is_err());
+    }
+
+    #[test]
+    fn test_has() {
+        let selectors = parse("a:has(> img, + .x, .a .b)").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 2, 1));
+        let relative = |combinator, selector| RelativeSelector {
+            combinator: combinator,
+            selector: parse(selector).unwrap().pop().unwrap(),
+        };
+        assert_eq!(rightmost(&selectors[0])[1],
+                   SimpleSelector::Has(vec![relative(Combinator::Child, "img"),
+                                            relative(Combinator::NextSibling, ".x"),
+                                            relative(Combinator::Descendant, ".a .b")]));
+        assert!(parse(":has()").is_err());
+        assert!(parse(":has(>)").is_err());
+        assert!(parse(":has(::before)").is_err());
+        assert_eq!(parse_error(":has(:has(a))"), (SelectorParseErrorKind::NestedHas, 1, 7));
+        assert_eq!(parse_error(":has(> :not(.a :has(b)))"),
+                   (SelectorParseErrorKind::NestedHas, 1, 18));
+        // `:is()` drops the selector instead.
+        assert_eq!(parse(":has(:is(:has(a), b))").unwrap()[0].to_css_string(), ":has(:is(b))");
+        assert!(parse(":has(a) :is(:has(b))").is_ok());
+    }
+
+    #[test]
+    fn test_is_and_where() {
+        let selectors = parse(":is(.a, #b > c, ::before, !, :where(#d))").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(1, 0, 1));
+        match rightmost(&selectors[0])[0] {
+            SimpleSelector::Is(ref arguments) => {
+                assert_eq!(arguments, &parse(".a, #b > c, :where(#d)").unwrap())
+            }
+            ref other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse("a:where(#b.c)").unwrap()[0].specificity, specificity(0, 0, 1));
+        assert_eq!(rightmost(&parse(":is()").unwrap()[0]),
+                   vec![SimpleSelector::Is(vec![])]);
+        assert_eq!(parse(":not(:is(.a, .b.c))").unwrap()[0].specificity, specificity(0, 2, 0));
+    }
+
+    #[test]
+    fn test_specificity() {
+        assert_eq!(specificity(1, 0, 2).to_string(), "(1,0,2)");
+        assert!(specificity(1, 0, 0) > specificity(0, 1023, 1023));
+        assert!(specificity(0, 2, 0) > specificity(0, 1, 5));
+        let mut many_classes = String::new();
+        for i in 0..2000 {
+            many_classes.push_str(&format!(".c{}", i));
+        }
+        let selectors = parse_unlimited(&many_classes).unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 2000, 0));
+        assert_eq!(selectors[0].specificity.b(), 2000);
+        assert!(selectors[0].specificity > specificity(0, 1999, 5));
+    }
+
+    #[test]
+    fn test_many_compound_selectors() {
+        let mut css = "a".to_owned();
+        for _ in 1..100000 {
+            css.push_str(" + a");
+        }
+        let selectors = parse_unlimited(&css).unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 0, 100000));
+        assert_eq!(selectors[0].to_css_string(), css);
+        // Dropping doesn't recurse either.
+        drop(selectors);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse_error(""), (SelectorParseErrorKind::EmptySelector, 1, 1));
+        assert_eq!(parse_error("a, , b"), (SelectorParseErrorKind::EmptySelector, 1, 4));
+        assert_eq!(parse_error("a >"), (SelectorParseErrorKind::DanglingCombinator, 1, 4));
+        assert_eq!(parse_error("a. b"), (SelectorParseErrorKind::ExpectedClassName, 1, 3));
+        assert_eq!(parse_error("a:foo"),
+                   (SelectorParseErrorKind::UnknownPseudoClass("foo".to_owned()), 1, 3));
+        assert_eq!(parse_error("a::foo"),
+                   (SelectorParseErrorKind::UnknownPseudoElement("foo".to_owned()), 1, 4));
+        assert_eq!(parse_error("svg|a"),
+                   (SelectorParseErrorKind::UndeclaredNamespacePrefix("svg".to_owned()), 1, 1));
+        assert_eq!(parse_error("[foo!=bar]"), (SelectorParseErrorKind::BadAttributeOperator, 1, 5));
+        assert_eq!(parse_error("[foo=]"), (SelectorParseErrorKind::ExpectedAttributeValue, 1, 6));
+        assert_eq!(parse_error("[foo=bar j]"), (SelectorParseErrorKind::BadAttributeFlags, 1, 10));
+        assert_eq!(parse_error("::before.foo"),
+                   (SelectorParseErrorKind::PseudoElementNotLast, 1, 9));
+        assert_eq!(parse_error(":nth-child(foo)"), (SelectorParseErrorKind::InvalidNth, 1, 12));
+        assert_eq!(parse_error(":not(a, ::before)"),
+                   (SelectorParseErrorKind::PseudoElementInArgument, 1, 9));
+        assert_eq!(parse_error(":not(a, b c!)"), (SelectorParseErrorKind::UnexpectedToken, 1, 12));
+        assert_eq!(parse_error("a\nb !"), (SelectorParseErrorKind::UnexpectedToken, 2, 3));
+    }
+
+    #[test]
+    fn test_parser_limits() {
+        fn limit_error(input: &str, limits: ParserLimits) -> (SelectorParseErrorKind, usize) {
+            let context = ParserContextBuilder::new().limits(limits).build();
+            let error = parse_ns(input, &context).unwrap_err();
+            (error.kind, error.location.column)
+        }
+        let exceeded = SelectorParseErrorKind::LimitExceeded;
+
+        let limits = ParserLimits { max_selectors_per_list: 2, ..Default::default() };
+        assert!(parse_ns("a, b", &ParserContextBuilder::new().limits(limits).build()).is_ok());
+        assert_eq!(limit_error("a, b, c", limits), (exceeded(ParserLimit::SelectorsPerList), 7));
+        assert_eq!(limit_error(":not(a, b, c)", limits),
+                   (exceeded(ParserLimit::SelectorsPerList), 12));
+        // Forgiving selector lists don't drop a selector that goes past a limit.
+        assert_eq!(limit_error(":is(a, b, c)", limits),
+                   (exceeded(ParserLimit::SelectorsPerList), 11));
+
+        let limits = ParserLimits { max_compound_selectors: 2, ..Default::default() };
+        assert_eq!(limit_error("a > b > c", limits),
+                   (exceeded(ParserLimit::CompoundSelectors), 9));
+        assert_eq!(limit_error(":where(a b c)", limits),
+                   (exceeded(ParserLimit::CompoundSelectors), 12));
+
+        let limits = ParserLimits { max_simple_selectors_per_compound: 2, ..Default::default() };
+        let context = ParserContextBuilder::new().limits(limits).build();
+        assert!(parse_ns("a.b", &context).is_ok());
+        assert!(parse_ns(".a::before", &context).is_ok());
+        assert!(parse_ns("::before:hover", &context).is_ok());
+        assert!(parse_ns(":not(a.b) :is(.c.d)", &context).is_ok());
+        assert_eq!(limit_error("a.b.c", limits),
+                   (exceeded(ParserLimit::SimpleSelectorsPerCompound), 4));
+        assert_eq!(limit_error("a.b::before", limits),
+                   (exceeded(ParserLimit::SimpleSelectorsPerCompound), 4));
+        assert_eq!(limit_error("::before:hover:focus", limits),
+                   (exceeded(ParserLimit::SimpleSelectorsPerCompound), 15));
+
+        let limits = ParserLimits { max_nesting_depth: 1, ..Default::default() };
+        assert_eq!(limit_error(":not(:not(a))", limits), (exceeded(ParserLimit::NestingDepth), 7));
+
+        let limits = ParserLimits { max_input_length: 10, ..Default::default() };
+        assert_eq!(limit_error(".aaaa .bbbb .cccc", limits),
+                   (exceeded(ParserLimit::InputLength), 7));
+        assert_eq!(limit_error(".aaaa, .bbbbbbbbbb", limits),
+                   (exceeded(ParserLimit::InputLength), 8));
+        let mut css = "[title='".to_owned();
+        for _ in 0..64 * 1024 {
+            css.push('a');
+        }
+        css.push_str("']");
+        assert_eq!(limit_error(&css, ParserLimits::default()),
+                   (exceeded(ParserLimit::InputLength), 1));
+        assert!(parse_ns("[title='aaaa']", &ParserContextBuilder::new().build()).is_ok());
+
+        let mut css = "a".to_owned();
+        for _ in 1..100000 {
+            css.push_str(" + a");
+        }
+        assert_eq!(parse(&css).unwrap_err().kind, exceeded(ParserLimit::CompoundSelectors));
+    }
+
+    #[test]
+    fn test_attribute_flags() {
+        let attr = AttrSelector {
+            name: atom!("lang"),
+            lower_name: atom!("lang"),
+            namespace: NamespaceConstraint::Specific(ns!("")),
+            prefix: None,
+        };
+        let simple_selector = |input: &str| {
+            rightmost(&parse(input).unwrap().pop().unwrap())[0].clone()
+        };
+        assert_eq!(simple_selector("[lang|=en]"),
+                   SimpleSelector::AttrDashMatch(attr.clone(), "en".to_owned(),
+                                                 CaseSensitivity::DocumentDefault));
+        assert_eq!(simple_selector("[lang~=en I]"),
+                   SimpleSelector::AttrIncludes(attr.clone(), "en".to_owned(),
+                                                CaseSensitivity::CaseInsensitive));
+        assert_eq!(simple_selector("[lang^='en' s]"),
+                   SimpleSelector::AttrPrefixMatch(attr.clone(), "en".to_owned(),
+                                                   CaseSensitivity::CaseSensitive));
+        assert_eq!(simple_selector("[lang*=en i]"),
+                   SimpleSelector::AttrSubstringMatch(attr.clone(), "en".to_owned(),
+                                                      CaseSensitivity::CaseInsensitive));
+        assert_eq!(simple_selector("[lang$=en S]"),
+                   SimpleSelector::AttrSuffixMatch(attr, "en".to_owned(),
+                                                   CaseSensitivity::CaseSensitive));
+        assert!(parse("[lang i]").is_err());
+        assert!(parse("[lang=en i s]").is_err());
+    }
+
+    #[test]
+    fn test_lang_and_dir() {
+        let selectors = parse("p:lang(en, \"*-CH\", de-DE)").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 1, 1));
+        assert_eq!(rightmost(&selectors[0])[1],
+                   SimpleSelector::Lang(vec!["en".to_owned(), "*-CH".to_owned(),
+                                             "de-DE".to_owned()]));
+        assert_eq!(rightmost(&parse(":dir(RTL)").unwrap()[0]),
+                   vec![SimpleSelector::Dir(Direction::Rtl)]);
+        assert!(parse(":lang()").is_err());
+        assert!(parse(":lang(en fr)").is_err());
+        assert!(parse(":lang(en,)").is_err());
+        assert!(parse(":dir(auto)").is_err());
+    }
+
+    #[test]
+    fn test_defined_and_state() {
+        let selectors = parse(":defined:state(loading)").unwrap();
+        assert_eq!(rightmost(&selectors[0]),
+                   vec![SimpleSelector::Defined,
+                        SimpleSelector::State(Atom::from_slice("loading"))]);
+        assert_eq!(parse("x-foo:state(a):defined").unwrap()[0].specificity,
+                   specificity(0, 2, 1));
+        assert!(parse(":state()").is_err());
+        assert!(parse(":state(a b)").is_err());
+        assert!(parse(":state('a')").is_err());
+    }
+
+    #[test]
+    fn test_host() {
+        assert_eq!(rightmost(&parse(":host").unwrap()[0]),
+                   vec![SimpleSelector::Host(None)]);
+        assert_eq!(rightmost(&parse(":host(.a)").unwrap()[0]),
+                   vec![SimpleSelector::Host(Some(vec![
+                       SimpleSelector::Class(Atom::from_slice("a"))]))]);
+        assert_eq!(parse(":host").unwrap()[0].specificity, specificity(0, 1, 0));
+        assert_eq!(parse(":host(div.a) > p").unwrap()[0].specificity, specificity(0, 2, 2));
+        assert_eq!(parse(":host-context(body.dark)").unwrap()[0].specificity,
+                   specificity(0, 2, 1));
+        assert!(parse(":host()").is_err());
+        assert!(parse(":host(a b)").is_err());
+        assert!(parse(":host-context(a, b)").is_err());
+        assert_eq!(parse_error(":host(::before)"),
+                   (SelectorParseErrorKind::PseudoElementInArgument, 1, 7));
+    }
+
+    #[test]
+    fn test_empty_target_scope_local_link() {
+        assert_eq!(rightmost(&parse(":scope:target").unwrap()[0]),
+                   vec![SimpleSelector::Scope, SimpleSelector::Target]);
+        assert_eq!(parse("p:empty").unwrap()[0].specificity, specificity(0, 1, 1));
+        assert_eq!(parse("a:local-link").unwrap()[0].specificity, specificity(0, 1, 1));
+        assert!(parse(":empty()").is_err());
+    }
+
+    #[test]
+    fn test_pseudo_elements() {
+        let pseudo_element = |input: &str| parse(input).unwrap().pop().unwrap().pseudo_element;
+        assert_eq!(pseudo_element("p:first-line"), Some(PseudoElement::FirstLine));
+        assert_eq!(pseudo_element("p::FIRST-LETTER"), Some(PseudoElement::FirstLetter));
+        assert_eq!(pseudo_element("::selection"), Some(PseudoElement::Selection));
+        assert_eq!(pseudo_element("input::placeholder"), Some(PseudoElement::Placeholder));
+        assert_eq!(pseudo_element("li::marker"), Some(PseudoElement::Marker));
+        assert_eq!(pseudo_element("dialog::backdrop"), Some(PseudoElement::Backdrop));
+        assert_eq!(pseudo_element("::file-selector-button"),
+                   Some(PseudoElement::FileSelectorButton));
+        assert_eq!(pseudo_element("::part( label  icon )"),
+                   Some(PseudoElement::Part(vec![Atom::from_slice("label"),
+                                                 Atom::from_slice("icon")])));
+        assert_eq!(pseudo_element("::highlight(search)"),
+                   Some(PseudoElement::Highlight(Atom::from_slice("search"))));
+        assert_eq!(pseudo_element("::slotted(img.a)"), Some(PseudoElement::Slotted(
+            rightmost(&parse("img.a").unwrap().pop().unwrap()))));
+        assert_eq!(parse("::slotted(img.a)").unwrap()[0].specificity, specificity(0, 1, 2));
+
+        // Only the CSS 2.1 pseudo-elements have a single-colon form.
+        assert!(parse(":selection").is_err());
+        assert!(parse("::part()").is_err());
+        assert!(parse("::part(a, b)").is_err());
+        assert!(parse("::highlight(a b)").is_err());
+        assert!(parse("::slotted(a b)").is_err());
+        assert!(parse("::slotted(a::before)").is_err());
+        assert_eq!(parse_error("::foo(a)"),
+                   (SelectorParseErrorKind::UnknownPseudoElement("foo".to_owned()), 1, 3));
+    }
+
+    #[test]
+    fn test_pseudo_element_state() {
+        let selectors = parse("a::before:hover:focus").unwrap();
+        assert_eq!(selectors[0].pseudo_element, Some(PseudoElement::Before));
+        assert_eq!(selectors[0].pseudo_element_state,
+                   vec![SimpleSelector::Hover, SimpleSelector::Focus]);
+        assert_eq!(selectors[0].specificity, specificity(0, 2, 2));
+        assert_eq!(parse("::part(label):hover").unwrap()[0].pseudo_element_state,
+                   vec![SimpleSelector::Hover]);
+        assert!(parse("::placeholder:focus").is_ok());
+        assert_eq!(parse("::after:active:focus-visible:focus-within").unwrap()[0]
+                       .pseudo_element_state,
+                   vec![SimpleSelector::Active, SimpleSelector::FocusVisible,
+                        SimpleSelector::FocusWithin]);
+        assert_eq!(parse_error("::before:hover.a"),
+                   (SelectorParseErrorKind::PseudoElementNotLast, 1, 15));
+        assert_eq!(parse_error("::before:first-child"),
+                   (SelectorParseErrorKind::PseudoElementNotLast, 1, 9));
+        assert_eq!(parse_error("::before:hover::after"),
+                   (SelectorParseErrorKind::PseudoElementNotLast, 1, 15));
+        assert_eq!(parse_error("::before:hover a"),
+                   (SelectorParseErrorKind::PseudoElementNotLast, 1, 16));
+    }
+
+    #[test]
+    fn test_selector_impl() {
+        assert_eq!(parse_error("a:-servo-nonzero-border"),
+                   (SelectorParseErrorKind::UnknownPseudoClass("-servo-nonzero-border".to_owned()),
+                    1, 3));
+        let context = ParserContextBuilder::new().in_user_agent_stylesheet(true).build();
+        let selectors = parse_ns("a:-servo-nonzero-border", &context).unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 1, 1));
+        assert_eq!(rightmost(&selectors[0])[1],
+                   SimpleSelector::NonTSPseudoClass(DummyPseudoClass::ServoNonzeroBorder));
+
+        let selectors = parse(":-servo-case-sensitive-type-attr(Foo)").unwrap();
+        assert_eq!(rightmost(&selectors[0]),
+                   vec![SimpleSelector::NonTSPseudoClass(
+                       DummyPseudoClass::ServoCaseSensitiveTypeAttr(Atom::from_slice("Foo")))]);
+        assert!(parse(":-servo-case-sensitive-type-attr(Foo bar)").is_err());
+
+        let selectors = parse("details::-SERVO-details-summary").unwrap();
+        assert_eq!(selectors[0].specificity, specificity(0, 0, 2));
+        assert_eq!(selectors[0].pseudo_element,
+                   Some(PseudoElement::Custom(DummyPseudoElement::DetailsSummary)));
+        // Only the standard CSS 2.1 pseudo-elements can have a single colon.
+        assert!(parse("details:-servo-details-summary").is_err());
+    }
+
+    fn check_serialization(input: &str, expected: &str, context: &ParserContext) {
+        let mut css = String::new();
+        serialize_selector_list(&parse_ns(input, context).unwrap(), &mut css).unwrap();
+        assert_eq!(css, expected);
+        assert_eq!(parse_ns(&css, context), parse_ns(input, context));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut builder = ParserContextBuilder::new();
+        builder.add_namespace(Some("svg"), ns!(SVG)).unwrap();
+        let context = builder.build();
+        check_serialization("*", "*", &context);
+        check_serialization("E", "E", &context);
+        check_serialization("svg|a, |b, *|c, svg|*", "svg|a, |b, c, svg|*", &context);
+        check_serialization("div>p~ a+b  c", "div > p ~ a + b c", &context);
+        check_serialization("#a\\ b.c\\.d", "#a\\ b.c\\.d", &context);
+        check_serialization("[foo][svg|href='x'][*|lang|=en][data-x=\"a\\\"b\" i]",
+                            "[foo][svg|href=\"x\"][*|lang|=\"en\"][data-x=\"a\\\"b\" i]",
+                            &context);
+        check_serialization("[a~=b][a^=b][a$=b][a*=b]",
+                            "[a~=\"b\"][a^=\"b\"][a$=\"b\"][a*=\"b\"]", &context);
+        check_serialization("[a=b s][a~=b I][a|=b S][a^=b i][a$=b s][a*=b i]",
+                            concat!("[a=\"b\" s][a~=\"b\" i][a|=\"b\" s]",
+                                    "[a^=\"b\" i][a$=\"b\" s][a*=\"b\" i]"),
+                            &context);
+        check_serialization(":not(svg|a):not(.b):not(*)", ":not(svg|a):not(.b):not(*)",
+                            &context);
+        check_serialization(
+            ":nth-child(odd):nth-last-child(-n+3):nth-of-type(0n+5):nth-last-of-type(2n)",
+            ":nth-child(2n+1):nth-last-child(-n+3):nth-of-type(5):nth-last-of-type(2n)",
+            &context);
+        check_serialization(":is(a > b, .c, :where()), :where(:is(*|d))",
+                            ":is(a > b, .c, :where()), :where(:is(d))", &context);
+        check_serialization(":nth-child(even of a,b):nth-last-child(1 of .c)",
+                            ":nth-child(2n of a, b):nth-last-child(1 of .c)", &context);
+        check_serialization(":has(>img,~ b  c, d)", ":has(> img, ~ b c, d)", &context);
+        check_serialization("x-foo:DEFINED:state( checked ):state(A\\ b)",
+                            "x-foo:defined:state(checked):state(A\\ b)", &context);
+        check_serialization(":HOST, :host( .a), :host-context(*|body) p",
+                            ":host, :host(.a), :host-context(body) p", &context);
+        check_serialization("a:ACTIVE, :focus-within > :focus-visible",
+                            "a:active, :focus-within > :focus-visible", &context);
+        check_serialization(":SCOPE > p:empty, :target, a:local-link",
+                            ":scope > p:empty, :target, a:local-link", &context);
+        check_serialization(":required:optional:valid:invalid:read-only:read-write",
+                            ":required:optional:valid:invalid:read-only:read-write", &context);
+        check_serialization(":Placeholder-Shown:default:in-range:out-of-range",
+                            ":placeholder-shown:default:in-range:out-of-range", &context);
+        check_serialization("a:hover::before, ::after, div :after",
+                            "a:hover::before, ::after, div ::after", &context);
+        check_serialization(":lang( en ,'*-CH',\"\"):dir(LTR)",
+                            ":lang(en, \"*-CH\", \"\"):dir(ltr)", &context);
+        check_serialization("a::before:hover, ::part(x):focus:hover",
+                            "a::before:hover, ::part(x):focus:hover", &context);
+        check_serialization("p:first-line, p:first-letter, ::selection, ::placeholder",
+                            "p::first-line, p::first-letter, ::selection, ::placeholder",
+                            &context);
+        check_serialization("::marker, ::backdrop, ::file-selector-button",
+                            "::marker, ::backdrop, ::file-selector-button", &context);
+        check_serialization("::part(a  b), ::slotted(svg|*.c), ::slotted(*), ::highlight(d)",
+                            "::part(a b), ::slotted(svg|*.c), ::slotted(*), ::highlight(d)",
+                            &context);
+        check_serialization(":-servo-case-sensitive-type-attr(type)::-servo-details-summary",
+                            ":-servo-case-sensitive-type-attr(type)::-servo-details-summary",
+                            &context);
+
+        builder.add_namespace(None, ns!(HTML)).unwrap();
+        check_serialization("*|a, a, *, *|*", "*|a, a, *, *|*", &builder.build());
+    }
+
+    #[test]
+    fn test_namespace_rules() {
+        fn parse_rule(builder: &mut ParserContextBuilder, input: &str)
+                      -> Result<(), NamespaceError> {
+            builder.parse_namespace_rule(&mut Parser::new(input))
+        }
+        let mut builder = ParserContextBuilder::new();
+        assert_eq!(parse_rule(&mut builder, "url(http://www.w3.org/1999/xhtml)"), Ok(()));
+        assert_eq!(parse_rule(&mut builder, "svg 'http://www.w3.org/2000/svg'"), Ok(()));
+        assert_eq!(parse_rule(&mut builder, "\"x\""),
+                   Err(NamespaceError::DuplicateDefaultNamespace));
+        assert_eq!(parse_rule(&mut builder, "svg url(x)"),
+                   Err(NamespaceError::DuplicatePrefix("svg".to_owned())));
+        assert_eq!(parse_rule(&mut builder, "SVG url(x)"), Ok(()));
+        assert_eq!(parse_rule(&mut builder, "a b url(x)"),
+                   Err(NamespaceError::InvalidRule));
+        assert_eq!(parse_rule(&mut builder, "a url(x) b"),
+                   Err(NamespaceError::InvalidRule));
+        assert_eq!(builder.add_namespace(Some(""), ns!(MathML)),
+                   Err(NamespaceError::InvalidPrefix("".to_owned())));
+
+        let context = builder.build();
+        assert_eq!(context.default_namespace(), Some(&ns!(HTML)));
+        assert_eq!(context.namespace_for_prefix("svg"), Some(&ns!(SVG)));
+        assert!(context.namespace_for_prefix("a").is_none());
+        assert_eq!(rightmost(&parse_ns("svg|a", &context).unwrap()[0])[0],
+                   SimpleSelector::Namespace(Some(Atom::from_slice("svg")), ns!(SVG)));
+    }
+
+    #[test]
+    fn test_parser_features() {
+        let context = ParserContextBuilder::new()
+            .disable_features(HAS_PSEUDO_CLASS | SHADOW_DOM_SELECTORS)
+            .build();
+        assert_eq!(parse_ns(":has(a)", &context).unwrap_err().kind,
+                   SelectorParseErrorKind::UnknownPseudoClass("has".to_owned()));
+        assert_eq!(parse_ns(":host", &context).unwrap_err().kind,
+                   SelectorParseErrorKind::UnknownPseudoClass("host".to_owned()));
+        assert_eq!(parse_ns("::part(a)", &context).unwrap_err().kind,
+                   SelectorParseErrorKind::UnknownPseudoElement("part".to_owned()));
+        assert!(parse_ns(":state(a)", &context).is_ok());
+
+        let context = ParserContextBuilder::new()
+            .disable_features(ParserFeatures::all())
+            .enable_features(CUSTOM_STATE_PSEUDO_CLASS)
+            .build();
+        assert_eq!(context.features(), CUSTOM_STATE_PSEUDO_CLASS);
+        assert!(parse_ns(":state(a)", &context).is_ok());
     }
 }
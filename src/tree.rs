@@ -5,9 +5,57 @@
 //! Traits that nodes must implement. Breaks the otherwise-cyclic dependency between layout and
 //! style.
 
-use parser::AttrSelector;
+use std::ascii::AsciiExt;
+
+use parser::{AttrSelector, Direction, SelectorImpl};
 use string_cache::{Atom, Namespace};
 
+bitflags! {
+    #[doc = "The states of an element that pseudo-classes match, as returned by"]
+    #[doc = "`TElement::get_state`."]
+    flags ElementState: u32 {
+        #[doc = "`:hover`"]
+        const IN_HOVER_STATE = 0x0001,
+        #[doc = "`:focus`"]
+        const IN_FOCUS_STATE = 0x0002,
+        #[doc = "`:enabled`"]
+        const IN_ENABLED_STATE = 0x0004,
+        #[doc = "`:disabled`"]
+        const IN_DISABLED_STATE = 0x0008,
+        #[doc = "`:checked`"]
+        const IN_CHECKED_STATE = 0x0010,
+        #[doc = "`:indeterminate`"]
+        const IN_INDETERMINATE_STATE = 0x0020,
+        #[doc = "`:required`"]
+        const IN_REQUIRED_STATE = 0x0040,
+        #[doc = "`:optional`"]
+        const IN_OPTIONAL_STATE = 0x0080,
+        #[doc = "`:valid`"]
+        const IN_VALID_STATE = 0x0100,
+        #[doc = "`:invalid`"]
+        const IN_INVALID_STATE = 0x0200,
+        #[doc = "`:read-only`"]
+        const IN_READ_ONLY_STATE = 0x0400,
+        #[doc = "`:read-write`"]
+        const IN_READ_WRITE_STATE = 0x0800,
+        #[doc = "`:placeholder-shown`"]
+        const IN_PLACEHOLDER_SHOWN_STATE = 0x1000,
+        #[doc = "`:default`"]
+        const IN_DEFAULT_STATE = 0x2000,
+        #[doc = "`:in-range`"]
+        const IN_IN_RANGE_STATE = 0x4000,
+        #[doc = "`:out-of-range`"]
+        const IN_OUT_OF_RANGE_STATE = 0x8000,
+        #[doc = "`:active`"]
+        const IN_ACTIVE_STATE = 0x10000,
+        #[doc = "`:focus-within`: set by the host on the focused element and its ancestors."]
+        const IN_FOCUS_WITHIN_STATE = 0x20000,
+        #[doc = "`:focus-visible`"]
+        const IN_FOCUS_VISIBLE_STATE = 0x40000,
+        #[doc = "`:defined`: a built-in element, or a custom element that has been upgraded."]
+        const IN_DEFINED_STATE = 0x80000,
+    }
+}
 
 pub trait TNode<'a>: Clone + Copy {
     type Element: TElement<'a>;
@@ -19,10 +67,95 @@ pub trait TNode<'a>: Clone + Copy {
     fn next_sibling(self) -> Option<Self>;
     fn is_document(self) -> bool;
     fn is_element(self) -> bool;
+    /// Whether this is a text node, or a CDATA section, whose data is not empty. Used by `:empty`.
+    fn is_non_empty_text_node(self) -> bool;
     fn as_element(self) -> Self::Element;
     fn match_attr<F>(self, attr: &AttrSelector, test: F) -> bool where F: Fn(&str) -> bool;
     fn is_html_element_in_html_document(self) -> bool;
 
+    /// Identifies this node among the nodes of its document for as long as it is alive.
+    /// Used to compare nodes and to key caches in `matching::MatchingContext`.
+    fn opaque(self) -> usize;
+
+    /// The language of this element, as matched by `:lang()`: that of the nearest `xml:lang` or
+    /// `lang` attribute on it or an ancestor, or `None` if there is none.
+    ///
+    /// Override this to also take into account e.g. a `Content-Language` `<meta>` pragma.
+    fn get_lang(self) -> Option<&'a str> {
+        let mut node = self;
+        loop {
+            if node.is_element() {
+                let element = node.as_element();
+                match element.get_attr(&ns!(XML), &atom!("lang")) {
+                    Some(lang) => return Some(lang),
+                    None => {}
+                }
+                match element.get_attr(&ns!(""), &atom!("lang")) {
+                    Some(lang) => return Some(lang),
+                    None => {}
+                }
+            }
+            match node.parent_node() {
+                Some(parent) => node = parent,
+                None => return None,
+            }
+        }
+    }
+
+    /// The directionality of this element, as matched by `:dir()`: that given by the nearest `dir`
+    /// attribute with a value of `ltr` or `rtl` on it or an ancestor, `ltr` if there is none.
+    ///
+    /// `dir=auto` depends on the text content, which this default does not look at: override
+    /// this to support it.
+    fn get_direction(self) -> Direction {
+        let mut node = self;
+        loop {
+            if node.is_element() {
+                match node.as_element().get_attr(&ns!(""), &atom!("dir")) {
+                    Some(dir) if dir.eq_ignore_ascii_case("ltr") => return Direction::Ltr,
+                    Some(dir) if dir.eq_ignore_ascii_case("rtl") => return Direction::Rtl,
+                    _ => {}
+                }
+            }
+            match node.parent_node() {
+                Some(parent) => node = parent,
+                None => return Direction::Ltr,
+            }
+        }
+    }
+
+    /// If this node is a shadow root, its host.
+    ///
+    /// Selector matching does not cross from a shadow tree into the tree of its host, except for
+    /// `:host` and `:host-context()`. Trees without shadow DOM don't need to override this.
+    fn shadow_root_host(self) -> Option<Self> {
+        None
+    }
+
+    /// The shadow root this node is in, if it is in a shadow tree.
+    fn containing_shadow_root(self) -> Option<Self> {
+        let mut node = self;
+        loop {
+            match node.parent_node() {
+                Some(parent) => node = parent,
+                None => return None,
+            }
+            if node.shadow_root_host().is_some() {
+                return Some(node)
+            }
+        }
+    }
+
+    /// The host of the shadow tree this node is in, if any.
+    fn containing_shadow_host(self) -> Option<Self> {
+        self.containing_shadow_root().and_then(|shadow_root| shadow_root.shadow_root_host())
+    }
+
+    /// The slot this node is assigned to, if any. Used by `::slotted()`.
+    fn assigned_slot(self) -> Option<Self> {
+        None
+    }
+
     fn has_changed(self) -> bool;
     unsafe fn set_changed(self, value: bool);
 
@@ -37,20 +170,29 @@ pub trait TNode<'a>: Clone + Copy {
 }
 
 pub trait TElement<'a>: Copy {
+    /// The vocabulary of the selectors this element is matched against.
+    type Impl: SelectorImpl;
+
     fn get_attr(self, namespace: &Namespace, attr: &Atom) -> Option<&'a str>;
     fn get_attrs(self, attr: &Atom) -> Vec<&'a str>;
+    /// The URL of the link this element is the source anchor of, if any. `:local-link` compares
+    /// it to the document URL as strings, so it should be absolute.
     fn get_link(self) -> Option<&'a str>;
     fn get_local_name(self) -> &'a Atom;
     fn get_namespace(self) -> &'a Namespace;
-    fn get_hover_state(self) -> bool;
-    fn get_focus_state(self) -> bool;
     fn get_id(self) -> Option<Atom>;
-    fn get_disabled_state(self) -> bool;
-    fn get_enabled_state(self) -> bool;
-    fn get_checked_state(self) -> bool;
-    fn get_indeterminate_state(self) -> bool;
+    /// The states this element is in, as defined by HTML for the corresponding pseudo-classes.
+    ///
+    /// https://html.spec.whatwg.org/multipage/semantics-other.html#pseudo-classes
+    fn get_state(self) -> ElementState;
     fn has_class(self, name: &Atom) -> bool;
-    fn has_nonzero_border(self) -> bool;
+    /// Whether the `CustomStateSet` of this custom element contains `state`, for `:state()`.
+    fn has_custom_state(self, state: &Atom) -> bool;
+
+    /// Whether this element matches a pseudo-class defined by `Self::Impl`.
+    fn match_non_ts_pseudo_class(self,
+                                 pseudo_class: &<Self::Impl as SelectorImpl>::NonTSPseudoClass)
+                                 -> bool;
 
     // Ordinarily I wouldn't use callbacks like this, but the alternative is
     // really messy, since there is a `JSRef` and a `RefCell` involved. Maybe
@@ -0,0 +1,364 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Traversal of the selector AST, for code that inspects or rewrites selectors without matching
+//! them.
+
+use std::iter::Rev;
+use std::vec;
+
+use parser::{Combinator, ComplexSelector, Component, ComponentMut, CompoundSelector};
+use parser::{PseudoElement, RelativeSelector, Selector, SelectorImpl, SimpleSelector};
+use parser::compute_specificity;
+
+
+/// Callbacks for `Selector::visit`. Each returns whether to continue the traversal.
+///
+/// Compound selectors are visited from right to left, like they are matched, each followed by
+/// the combinator to its left. The selectors nested in a simple selector, like the arguments of
+/// `:not()`, are visited right after it.
+pub trait SelectorVisitor<Impl: SelectorImpl> {
+    fn visit_simple_selector(&mut self, _selector: &SimpleSelector<Impl>) -> bool {
+        true
+    }
+
+    fn visit_combinator(&mut self, _combinator: Combinator) -> bool {
+        true
+    }
+
+    /// The pseudo-element is visited after the compound selectors, then the pseudo-classes after
+    /// it.
+    fn visit_pseudo_element(&mut self, _pseudo_element: &PseudoElement<Impl>) -> bool {
+        true
+    }
+
+    /// Called with the argument of `:not()`, `:is()`, `:where()` or `:nth-child(An+B of S)`
+    /// before visiting the selectors in it.
+    fn visit_selector_list(&mut self, _selectors: &[Selector<Impl>]) -> bool {
+        true
+    }
+
+    /// Called with the argument of `:has()` before visiting the selectors in it. The combinator
+    /// of each relative selector is visited after its compound selectors.
+    fn visit_relative_selector_list(&mut self,
+                                    _relative_selectors: &[RelativeSelector<Impl>])
+                                    -> bool {
+        true
+    }
+}
+
+/// Like `SelectorVisitor`, but for `Selector::visit_mut`, which can rewrite selectors.
+///
+/// The specificity of each `Selector` is computed again once it has been visited.
+pub trait SelectorVisitorMut<Impl: SelectorImpl> {
+    fn visit_simple_selector(&mut self, _selector: &mut SimpleSelector<Impl>) -> bool {
+        true
+    }
+
+    fn visit_combinator(&mut self, _combinator: &mut Combinator) -> bool {
+        true
+    }
+
+    fn visit_pseudo_element(&mut self, _pseudo_element: &mut PseudoElement<Impl>) -> bool {
+        true
+    }
+
+    fn visit_selector_list(&mut self, _selectors: &mut Vec<Selector<Impl>>) -> bool {
+        true
+    }
+
+    fn visit_relative_selector_list(&mut self,
+                                    _relative_selectors: &mut Vec<RelativeSelector<Impl>>)
+                                    -> bool {
+        true
+    }
+}
+
+
+/// The compound selectors of a complex selector from right to left, each with the combinator to
+/// its left, if any.
+pub struct CompoundSelectors<'a, Impl: SelectorImpl + 'a> {
+    next: Option<CompoundSelector<'a, Impl>>,
+}
+
+impl<'a, Impl: SelectorImpl> Iterator for CompoundSelectors<'a, Impl> {
+    type Item = (CompoundSelector<'a, Impl>, Option<Combinator>);
+
+    fn next(&mut self) -> Option<(CompoundSelector<'a, Impl>, Option<Combinator>)> {
+        match self.next {
+            None => None,
+            Some(compound_selector) => {
+                let next = compound_selector.next();
+                self.next = next.map(|(next, _)| next);
+                Some((compound_selector, next.map(|(_, combinator)| combinator)))
+            }
+        }
+    }
+}
+
+impl<'a, Impl: SelectorImpl> CompoundSelector<'a, Impl> {
+    /// `self` and the compound selectors to its left, from right to left.
+    pub fn compounds_right_to_left(self) -> CompoundSelectors<'a, Impl> {
+        CompoundSelectors {
+            next: Some(self),
+        }
+    }
+
+    /// Like `compounds_right_to_left`, in the other order.
+    pub fn compounds_left_to_right(self)
+                                   -> Rev<vec::IntoIter<(CompoundSelector<'a, Impl>,
+                                                         Option<Combinator>)>> {
+        self.compounds_right_to_left().collect::<Vec<_>>().into_iter().rev()
+    }
+}
+
+impl<Impl: SelectorImpl> ComplexSelector<Impl> {
+    pub fn visit<V>(&self, visitor: &mut V) -> bool where V: SelectorVisitor<Impl> {
+        self.components().iter().all(|component| {
+            match *component {
+                Component::SimpleSelector(ref simple_selector) => simple_selector.visit(visitor),
+                Component::Combinator(combinator) => visitor.visit_combinator(combinator),
+            }
+        })
+    }
+
+    pub fn visit_mut<V>(&mut self, visitor: &mut V) -> bool where V: SelectorVisitorMut<Impl> {
+        self.components_mut().all(|component| {
+            match component {
+                ComponentMut::SimpleSelector(simple_selector) => simple_selector.visit_mut(visitor),
+                ComponentMut::Combinator(combinator) => visitor.visit_combinator(combinator),
+            }
+        })
+    }
+}
+
+impl<Impl: SelectorImpl> Selector<Impl> {
+    pub fn compounds_right_to_left(&self) -> CompoundSelectors<Impl> {
+        self.complex_selector.rightmost_compound().compounds_right_to_left()
+    }
+
+    pub fn compounds_left_to_right(&self)
+                                   -> Rev<vec::IntoIter<(CompoundSelector<Impl>,
+                                                         Option<Combinator>)>> {
+        self.complex_selector.rightmost_compound().compounds_left_to_right()
+    }
+
+    /// Returns false if the visitor stopped the traversal.
+    pub fn visit<V>(&self, visitor: &mut V) -> bool where V: SelectorVisitor<Impl> {
+        self.complex_selector.visit(visitor) &&
+        self.pseudo_element.as_ref().map_or(true, |pseudo_element| {
+            pseudo_element.visit(visitor)
+        }) &&
+        self.pseudo_element_state.iter().all(|simple_selector| simple_selector.visit(visitor))
+    }
+
+    /// Returns false if the visitor stopped the traversal.
+    ///
+    /// The complex selector is cloned first if it is shared with another `Selector`.
+    pub fn visit_mut<V>(&mut self, visitor: &mut V) -> bool where V: SelectorVisitorMut<Impl> {
+        let result = self.complex_selector.make_unique().visit_mut(visitor) &&
+            self.pseudo_element.as_mut().map_or(true, |pseudo_element| {
+                pseudo_element.visit_mut(visitor)
+            }) &&
+            self.pseudo_element_state.iter_mut().all(|simple_selector| {
+                simple_selector.visit_mut(visitor)
+            });
+        self.specificity = compute_specificity(&self.complex_selector, &self.pseudo_element,
+                                               &self.pseudo_element_state);
+        result
+    }
+}
+
+impl<Impl: SelectorImpl> PseudoElement<Impl> {
+    pub fn visit<V>(&self, visitor: &mut V) -> bool where V: SelectorVisitor<Impl> {
+        if !visitor.visit_pseudo_element(self) {
+            return false
+        }
+        match *self {
+            PseudoElement::Slotted(ref simple_selectors) => {
+                simple_selectors.iter().all(|simple_selector| simple_selector.visit(visitor))
+            }
+            _ => true,
+        }
+    }
+
+    pub fn visit_mut<V>(&mut self, visitor: &mut V) -> bool where V: SelectorVisitorMut<Impl> {
+        if !visitor.visit_pseudo_element(self) {
+            return false
+        }
+        match *self {
+            PseudoElement::Slotted(ref mut simple_selectors) => {
+                simple_selectors.iter_mut().all(|simple_selector| {
+                    simple_selector.visit_mut(visitor)
+                })
+            }
+            _ => true,
+        }
+    }
+}
+
+impl<Impl: SelectorImpl> SimpleSelector<Impl> {
+    pub fn visit<V>(&self, visitor: &mut V) -> bool where V: SelectorVisitor<Impl> {
+        if !visitor.visit_simple_selector(self) {
+            return false
+        }
+        match *self {
+            SimpleSelector::Negation(ref selectors) |
+            SimpleSelector::Is(ref selectors) |
+            SimpleSelector::Where(ref selectors) |
+            SimpleSelector::NthChildOf(_, _, ref selectors) |
+            SimpleSelector::NthLastChildOf(_, _, ref selectors) => {
+                visitor.visit_selector_list(selectors) &&
+                selectors.iter().all(|selector| selector.visit(visitor))
+            }
+            SimpleSelector::Has(ref relative_selectors) => {
+                visitor.visit_relative_selector_list(relative_selectors) &&
+                relative_selectors.iter().all(|relative_selector| {
+                    relative_selector.selector.visit(visitor) &&
+                    visitor.visit_combinator(relative_selector.combinator)
+                })
+            }
+            SimpleSelector::Host(Some(ref simple_selectors)) |
+            SimpleSelector::HostContext(ref simple_selectors) => {
+                simple_selectors.iter().all(|simple_selector| simple_selector.visit(visitor))
+            }
+            _ => true,
+        }
+    }
+
+    pub fn visit_mut<V>(&mut self, visitor: &mut V) -> bool where V: SelectorVisitorMut<Impl> {
+        if !visitor.visit_simple_selector(self) {
+            return false
+        }
+        match *self {
+            SimpleSelector::Negation(ref mut selectors) |
+            SimpleSelector::Is(ref mut selectors) |
+            SimpleSelector::Where(ref mut selectors) |
+            SimpleSelector::NthChildOf(_, _, ref mut selectors) |
+            SimpleSelector::NthLastChildOf(_, _, ref mut selectors) => {
+                visitor.visit_selector_list(selectors) &&
+                selectors.iter_mut().all(|selector| selector.visit_mut(visitor))
+            }
+            SimpleSelector::Has(ref mut relative_selectors) => {
+                visitor.visit_relative_selector_list(relative_selectors) &&
+                relative_selectors.iter_mut().all(|relative_selector| {
+                    relative_selector.selector.visit_mut(visitor) &&
+                    visitor.visit_combinator(&mut relative_selector.combinator)
+                })
+            }
+            SimpleSelector::Host(Some(ref mut simple_selectors)) |
+            SimpleSelector::HostContext(ref mut simple_selectors) => {
+                simple_selectors.iter_mut().all(|simple_selector| {
+                    simple_selector.visit_mut(visitor)
+                })
+            }
+            _ => true,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cssparser::ToCss;
+    use string_cache::Atom;
+    use matching::tests::DummySelectorImpl;
+    use parser::{Combinator, Component, Selector, SimpleSelector, Specificity};
+    use parser::parse_author_origin_selector_list_from_str;
+    use super::{SelectorVisitor, SelectorVisitorMut};
+
+    fn parse(input: &str) -> Selector<DummySelectorImpl> {
+        parse_author_origin_selector_list_from_str(input).unwrap().pop().unwrap()
+    }
+
+    /// Records the classes and combinators, stopping at the class `stop`.
+    struct Recorder {
+        visited: Vec<String>,
+    }
+
+    impl SelectorVisitor<DummySelectorImpl> for Recorder {
+        fn visit_simple_selector(&mut self, selector: &SimpleSelector<DummySelectorImpl>)
+                                 -> bool {
+            match *selector {
+                SimpleSelector::Class(ref class) => {
+                    self.visited.push(format!(".{}", &**class));
+                    &**class != "stop"
+                }
+                _ => true,
+            }
+        }
+
+        fn visit_combinator(&mut self, combinator: Combinator) -> bool {
+            self.visited.push(format!("{:?}", combinator));
+            true
+        }
+
+        fn visit_selector_list(&mut self, selectors: &[Selector<DummySelectorImpl>]) -> bool {
+            self.visited.push(format!("list of {}", selectors.len()));
+            true
+        }
+    }
+
+    #[test]
+    fn test_visit() {
+        let mut recorder = Recorder { visited: vec![] };
+        assert!(parse(".a > .b:not(.c, .d ~ .e):has(+ .f)").visit(&mut recorder));
+        assert_eq!(recorder.visited, vec![
+            ".b", "list of 2", ".c", ".e", "LaterSibling", ".d", ".f", "NextSibling", "Child",
+            ".a"]);
+
+        let mut recorder = Recorder { visited: vec![] };
+        assert!(!parse(".a .stop .b").visit(&mut recorder));
+        assert_eq!(recorder.visited, vec![".b", "Descendant", ".stop"]);
+    }
+
+    #[test]
+    fn test_compound_iterators() {
+        let selector = parse("a > b + c");
+        let right_to_left = selector.compounds_right_to_left().map(|(compound, combinator)| {
+            (compound.simple_selectors().count(), combinator)
+        }).collect::<Vec<_>>();
+        assert_eq!(right_to_left, vec![(1, Some(Combinator::NextSibling)),
+                                       (1, Some(Combinator::Child)),
+                                       (1, None)]);
+        let left_to_right = selector.compounds_left_to_right().map(|(_, combinator)| {
+            combinator
+        }).collect::<Vec<_>>();
+        assert_eq!(left_to_right, vec![None, Some(Combinator::Child),
+                                       Some(Combinator::NextSibling)]);
+    }
+
+    /// Replaces classes with IDs of the same name.
+    struct ClassToId;
+
+    impl SelectorVisitorMut<DummySelectorImpl> for ClassToId {
+        fn visit_simple_selector(&mut self, selector: &mut SimpleSelector<DummySelectorImpl>)
+                                 -> bool {
+            let id = match *selector {
+                SimpleSelector::Class(ref class) => class.clone(),
+                _ => return true,
+            };
+            *selector = SimpleSelector::ID(id);
+            true
+        }
+    }
+
+    #[test]
+    fn test_visit_mut() {
+        let mut selector = parse(".a :is(.b, c)");
+        let shared = selector.clone();
+        assert!(selector.visit_mut(&mut ClassToId));
+        assert_eq!(selector.to_css_string(), "#a :is(#b, c)");
+        assert_eq!(selector.specificity, Specificity::new(2, 0, 0));
+        match selector.complex_selector.components()[0] {
+            Component::SimpleSelector(SimpleSelector::Is(ref selectors)) => {
+                assert_eq!(selectors[0].specificity, Specificity::new(1, 0, 0))
+            }
+            ref other => panic!("unexpected {:?}", other),
+        }
+        // The original is left alone.
+        assert_eq!(shared.complex_selector.components()[2],
+                   Component::SimpleSelector(SimpleSelector::Class(Atom::from_slice("a"))));
+    }
+}